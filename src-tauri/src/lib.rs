@@ -1,14 +1,19 @@
 use clap::Parser;
 use image::{ImageBuffer, Rgba};
 use serde::{Deserialize, Serialize};
-use similar::{ChangeTag, TextDiff};
-use std::cmp::Reverse;
+use similar::algorithms::{Capture, DiffHook};
+use similar::{ChangeTag, DiffOp, DiffableStr, TextDiff};
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
-use std::sync::OnceLock;
-use std::time::Instant;
-use xxhash_rust::xxh3::xxh3_64;
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use xxhash_rust::xxh3::Xxh3;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
 use rayon::prelude::*;
 use tracing::{info, debug};
 use tauri::Emitter;
@@ -18,15 +23,71 @@ use tauri::Emitter;
 #[command(name = "diffvibe")]
 #[command(about = "A visual diff and merge tool")]
 pub struct CliArgs {
-    /// Files to compare or merge (2 for diff, 3 for merge: local base remote)
+    /// Files or directories to compare or merge (2 for diff, 3 for merge: local base remote)
     #[arg(value_name = "FILE")]
     pub files: Vec<String>,
 
     /// Output file for merged result (merge mode only)
     #[arg(short, long)]
     pub output: Option<String>,
+
+    /// Print a unified diff to stdout instead of opening a window
+    #[arg(long)]
+    pub print: bool,
+
+    /// Like --print, with N lines of context (default 3)
+    #[arg(short = 'u', long, value_name = "N", num_args = 0..=1, require_equals = true, default_missing_value = "3")]
+    pub unified: Option<usize>,
+
+    /// Ignore all white space when comparing lines
+    #[arg(short = 'w', long)]
+    pub ignore_all_space: bool,
+
+    /// Ignore changes in the amount of white space
+    #[arg(short = 'b', long)]
+    pub ignore_space_change: bool,
+
+    /// Ignore white space at line end
+    #[arg(short = 'Z', long)]
+    pub ignore_trailing_space: bool,
+
+    /// Ignore case differences
+    #[arg(short = 'i', long)]
+    pub ignore_case: bool,
+
+    /// Ignore changes whose lines are all blank
+    #[arg(short = 'B', long)]
+    pub ignore_blank_lines: bool,
+
+    /// Diff algorithm for diffs and merges
+    #[arg(long, value_enum, default_value_t = DiffAlgorithm::Myers)]
+    pub diff_algorithm: DiffAlgorithm,
+
+    /// Time budget for a diff in milliseconds before falling back to a coarser result (0 = no limit)
+    #[arg(long, value_name = "MS", default_value_t = DEFAULT_DIFF_TIMEOUT_MS)]
+    pub diff_timeout: u64,
+
+    /// Merge three files without opening a window, writing to --output (or stdout).
+    /// Exits 0 when clean, 1 when conflicts remain, 2 on error.
+    #[arg(long)]
+    pub auto_merge: bool,
+
+    /// Conflict marker style for merge mode
+    #[arg(long, value_enum, default_value_t = ConflictStyle::Merge)]
+    pub conflict_style: ConflictStyle,
+
+    /// Conflict marker label; give up to three times for local, base and remote (e.g. git's $LOCAL/$REMOTE names)
+    #[arg(short = 'L', long = "label", value_name = "LABEL")]
+    pub labels: Vec<String>,
+
+    /// Length of conflict markers
+    #[arg(long, value_name = "N", default_value_t = 7)]
+    pub marker_size: usize,
 }
 
+/// Default number of context lines around each hunk in unified output
+const DEFAULT_CONTEXT_LINES: usize = 3;
+
 /// Parsed CLI mode
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "mode")]
@@ -34,7 +95,7 @@ pub enum CliMode {
     /// No files specified - show home
     None,
     /// Two files - diff mode
-    Diff { left: String, right: String },
+    Diff { left: String, right: String, options: DiffOptions },
     /// Two directories - directory diff mode
     DirDiff { left: String, right: String },
     /// Three files - merge mode (local, base, remote)
@@ -43,6 +104,14 @@ pub enum CliMode {
         base: String,
         remote: String,
         output: Option<String>,
+        options: MergeOptions,
+    },
+    /// Three directories - directory merge mode (local, base, remote)
+    DirMerge {
+        local: String,
+        base: String,
+        remote: String,
+        options: MergeOptions,
     },
 }
 
@@ -52,6 +121,20 @@ static CLI_ARGS: OnceLock<CliMode> = OnceLock::new();
 /// Parse CLI args and store globally
 pub fn parse_cli_args() {
     let args = CliArgs::parse();
+
+    // Headless mode: print the diff and exit without starting the GUI
+    if args.print || args.unified.is_some() {
+        attach_parent_console();
+        init_tracing("warn");
+        let context = args.unified.unwrap_or(DEFAULT_CONTEXT_LINES);
+        std::process::exit(run_print_mode(&args.files, context, diff_options_from_args(&args)));
+    }
+    if args.auto_merge {
+        attach_parent_console();
+        init_tracing("warn");
+        std::process::exit(run_auto_merge(&args));
+    }
+
     let mode = match args.files.len() {
         0 => CliMode::None,
         2 => {
@@ -65,24 +148,158 @@ pub fn parse_cli_args() {
             if left_is_dir && right_is_dir {
                 CliMode::DirDiff { left, right }
             } else {
-                CliMode::Diff { left, right }
+                CliMode::Diff { left, right, options: diff_options_from_args(&args) }
             }
         },
-        3 => CliMode::Merge {
-            local: args.files[0].clone(),
-            base: args.files[1].clone(),
-            remote: args.files[2].clone(),
-            output: args.output,
+        3 => {
+            let local = args.files[0].clone();
+            let base = args.files[1].clone();
+            let remote = args.files[2].clone();
+            let options = merge_options_from_args(&args);
+
+            if [&local, &base, &remote].iter().all(|p| Path::new(p).is_dir()) {
+                CliMode::DirMerge { local, base, remote, options }
+            } else {
+                CliMode::Merge { local, base, remote, output: args.output.clone(), options }
+            }
         },
         _ => {
             eprintln!("Usage: diffvibe <left> <right>           # diff mode");
-            eprintln!("       diffvibe <local> <base> <remote>  # merge mode");
+            eprintln!("       diffvibe <local> <base> <remote>  # merge mode (files or directories)");
+            eprintln!("       diffvibe --print <left> <right>   # print unified diff");
+            eprintln!("       diffvibe --auto-merge <local> <base> <remote> -o <output>");
             std::process::exit(1);
         }
     };
     CLI_ARGS.set(mode).ok();
 }
 
+/// Build line comparison options from CLI flags
+fn diff_options_from_args(args: &CliArgs) -> DiffOptions {
+    DiffOptions {
+        ignore_all_whitespace: args.ignore_all_space,
+        ignore_whitespace_amount: args.ignore_space_change,
+        ignore_trailing_whitespace: args.ignore_trailing_space,
+        ignore_case: args.ignore_case,
+        ignore_blank_lines: args.ignore_blank_lines,
+        algorithm: args.diff_algorithm,
+        timeout_ms: Some(args.diff_timeout).filter(|&ms| ms > 0),
+    }
+}
+
+/// Build merge marker options from CLI flags. Labels are positional:
+/// local, base, remote; missing ones keep their defaults.
+fn merge_options_from_args(args: &CliArgs) -> MergeOptions {
+    let mut options = MergeOptions {
+        conflict_style: args.conflict_style,
+        marker_size: args.marker_size,
+        algorithm: args.diff_algorithm,
+        ..MergeOptions::default()
+    };
+    let targets = [&mut options.local_label, &mut options.base_label, &mut options.remote_label];
+    for (target, label) in targets.into_iter().zip(&args.labels) {
+        *target = label.clone();
+    }
+    options
+}
+
+/// Headless three-way merge for git mergetool and scripts.
+/// Returns 0 when the merge is clean, 1 when conflicts remain, 2 on error.
+fn run_auto_merge(args: &CliArgs) -> i32 {
+    let [local, base, remote] = args.files.as_slice() else {
+        eprintln!("diffvibe: --auto-merge requires three files: <local> <base> <remote>");
+        return 2;
+    };
+
+    let options = merge_options_from_args(args);
+    match auto_merge_files(local, base, remote, args.output.as_deref(), &options) {
+        Ok(0) => 0,
+        Ok(conflicts) => {
+            eprintln!("diffvibe: {} conflict(s) remain", conflicts);
+            1
+        }
+        Err(e) => {
+            eprintln!("diffvibe: {}", e);
+            2
+        }
+    }
+}
+
+/// Merge three files and write the result (with conflict markers, if any)
+/// to `output`, or stdout when no output is given. Returns the conflict count.
+fn auto_merge_files(
+    local_path: &str,
+    base_path: &str,
+    remote_path: &str,
+    output: Option<&str>,
+    options: &MergeOptions,
+) -> Result<usize, String> {
+    let mut files = Vec::new();
+    for path in [local_path, base_path, remote_path] {
+        let file = read_file(path)?;
+        if !file.exists {
+            return Err(format!("{}: No such file or directory", path));
+        }
+        if file.is_binary {
+            return Err(format!("{}: cannot merge binary files", path));
+        }
+        files.push(file);
+    }
+    let [local, base, remote] = [&files[0], &files[1], &files[2]];
+
+    let result = compute_three_way_diff(&base.content, &local.content, &remote.content, Some(options.clone()));
+    if let Some(mismatch) = &result.line_ending_mismatch {
+        eprintln!("diffvibe: warning: {}", mismatch);
+    }
+
+    // Keep the local file's encoding for the merged result
+    let bytes = encode_content(&result.merged_content, &local.encoding);
+    match output {
+        Some(path) => {
+            if let Some(parent) = Path::new(path).parent().filter(|p| !p.as_os_str().is_empty()) {
+                fs::create_dir_all(parent).map_err(|e| format!("Failed to create directory: {}", e))?;
+            }
+            write_atomic(Path::new(path), &bytes)?;
+        }
+        None => {
+            use std::io::Write;
+            let mut stdout = std::io::stdout().lock();
+            stdout
+                .write_all(&bytes)
+                .and_then(|_| stdout.flush())
+                .map_err(|e| format!("Failed to write output: {}", e))?;
+        }
+    }
+
+    Ok(result.conflict_count)
+}
+
+/// Write a unified diff of two files to stdout.
+/// Returns a diff(1)-style exit code: 0 identical, 1 different, 2 error.
+fn run_print_mode(files: &[String], context: usize, options: DiffOptions) -> i32 {
+    use std::io::Write;
+
+    let [left, right] = files else {
+        eprintln!("diffvibe: --print requires exactly two files");
+        return 2;
+    };
+
+    match unified_diff_files(left, right, context, options) {
+        Ok(None) => 0,
+        Ok(Some(output)) => {
+            let mut stdout = std::io::stdout().lock();
+            if stdout.write_all(output.as_bytes()).and_then(|_| stdout.flush()).is_err() {
+                return 2;
+            }
+            1
+        }
+        Err(e) => {
+            eprintln!("diffvibe: {}", e);
+            2
+        }
+    }
+}
+
 #[tauri::command]
 fn get_cli_args() -> CliMode {
     CLI_ARGS.get().cloned().unwrap_or(CliMode::None)
@@ -102,6 +319,28 @@ pub struct FileContent {
     pub line_count: usize,
     pub is_binary: bool,
     pub exists: bool,
+    /// Identifies this version of the file for `write_file`; `None` if it doesn't exist
+    pub version: Option<String>,
+}
+
+/// Why `write_file` didn't save
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum WriteError {
+    /// The file changed since it was read. `actual` is its current version,
+    /// or `None` if it has been deleted.
+    ModifiedOnDisk {
+        path: String,
+        expected: String,
+        actual: Option<String>,
+    },
+    Io { message: String },
+}
+
+impl From<String> for WriteError {
+    fn from(message: String) -> Self {
+        WriteError::Io { message }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -110,12 +349,97 @@ pub struct DiffLine {
     pub old_index: Option<usize>,
     pub new_index: Option<usize>,
     pub value: String,
+    pub new_value: Option<String>, // Right-side text of an equal line, when ignore options let it differ
+    pub word_spans: Vec<InlineSpan>, // Changed words vs the paired line (replace hunks only)
+    pub char_spans: Vec<InlineSpan>, // Changed characters vs the paired line (replace hunks only)
+    pub move_index: Option<usize>,   // Index into DiffResult.moves for moved lines
+    pub ignored: bool,               // Part of a blank-lines-only hunk hidden by ignore_blank_lines
+}
+
+impl DiffLine {
+    fn new(tag: &str, old_index: Option<usize>, new_index: Option<usize>, value: &str) -> Self {
+        Self {
+            tag: tag.to_string(),
+            old_index,
+            new_index,
+            value: value.to_string(),
+            new_value: None,
+            word_spans: Vec::new(),
+            char_spans: Vec::new(),
+            move_index: None,
+            ignored: false,
+        }
+    }
+}
+
+/// Emphasized range within a line, in UTF-16 code units so the frontend can
+/// slice JS strings directly
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InlineSpan {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Line diff algorithm (mirrors git's --diff-algorithm)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffAlgorithm {
+    /// Classic shortest-edit-script diff
+    #[default]
+    Myers,
+    /// Anchors on lines unique to both sides; aligns code blocks better
+    Patience,
+    /// Longest common subsequence
+    Lcs,
+    /// Anchors on the least frequent common lines, like git's histogram diff
+    Histogram,
+}
+
+/// Default diff time budget; past it the diff gets coarser instead of hanging
+const DEFAULT_DIFF_TIMEOUT_MS: u64 = 5_000;
+
+/// Line comparison options for compute_diff (ignore options are off by default)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiffOptions {
+    pub ignore_all_whitespace: bool,
+    pub ignore_whitespace_amount: bool,
+    pub ignore_trailing_whitespace: bool,
+    pub ignore_case: bool,
+    pub ignore_blank_lines: bool,
+    pub algorithm: DiffAlgorithm,
+    pub timeout_ms: Option<u64>, // None = no limit
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            ignore_all_whitespace: false,
+            ignore_whitespace_amount: false,
+            ignore_trailing_whitespace: false,
+            ignore_case: false,
+            ignore_blank_lines: false,
+            algorithm: DiffAlgorithm::Myers,
+            timeout_ms: Some(DEFAULT_DIFF_TIMEOUT_MS),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DiffResult {
     pub lines: Vec<DiffLine>,
     pub stats: DiffStats,
+    pub moves: Vec<MovedBlock>,
+}
+
+/// A block of lines deleted in one place and inserted elsewhere. Indices are
+/// 0-based line numbers in the old and new text.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MovedBlock {
+    pub old_start: usize,
+    pub new_start: usize,
+    pub count: usize,
+    pub exact: bool, // false when lines differ in whitespace only
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -131,9 +455,12 @@ pub enum ChunkType {
     Equal,      // Same in all three
     LocalOnly,  // Changed only in local
     RemoteOnly, // Changed only in remote
+    BothSame,   // Changed identically in both
     Conflict,   // Changed in both (differently)
 }
 
+/// A contiguous region of the merge. `base_start`/`base_count` locate it in base
+/// (0-based lines); `local_lines`/`remote_lines` hold each side's version of it.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MergeChunk {
     pub chunk_type: ChunkType,
@@ -143,50 +470,92 @@ pub struct MergeChunk {
     pub remote_lines: Vec<String>,
 }
 
+/// How conflicts are written into merged_content (mirrors git's merge.conflictStyle)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictStyle {
+    /// Local and remote sections only
+    #[default]
+    Merge,
+    /// Adds a `|||||||` section with the base lines
+    Diff3,
+    /// Like diff3, with lines common to both sides moved outside the markers
+    Zdiff3,
+}
+
+/// Conflict marker options for compute_three_way_diff
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MergeOptions {
+    pub conflict_style: ConflictStyle,
+    pub local_label: String,
+    pub base_label: String,
+    pub remote_label: String,
+    pub marker_size: usize,
+    pub algorithm: DiffAlgorithm,
+}
+
+impl Default for MergeOptions {
+    fn default() -> Self {
+        Self {
+            conflict_style: ConflictStyle::Merge,
+            local_label: "LOCAL".to_string(),
+            base_label: "BASE".to_string(),
+            remote_label: "REMOTE".to_string(),
+            marker_size: 7,
+            algorithm: DiffAlgorithm::Myers,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MergeResult {
     pub chunks: Vec<MergeChunk>,
     pub conflict_count: usize,
     pub merged_content: String,  // Auto-merged with conflict markers
+    pub line_ending: String,     // "lf" or "crlf", as used in merged_content
+    pub final_newline: bool,     // Whether merged_content ends with a line ending
+    pub line_ending_mismatch: Option<String>, // Set when the inputs disagree on line endings
 }
 
 // Directory comparison types
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-pub enum FileStatus {
-    Identical,
-    Modified,
-    LeftOnly,
-    RightOnly,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct CompareEntry {
-    pub name: String,
-    pub rel_path: String,  // Relative path from root
-    pub is_dir: bool,
-    pub status: FileStatus,
-    pub left_size: Option<u64>,
-    pub right_size: Option<u64>,
-    pub children: Vec<CompareEntry>,
-}
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct DirectoryCompareResult {
-    pub left_path: String,
-    pub right_path: String,
-    pub entries: Vec<CompareEntry>,
-    pub stats: CompareStats,
+/// How files of equal size are checked for identical content
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompareMethod {
+    /// Equal size counts as identical (fastest, misses same-length edits)
+    Size,
+    /// Full-content xxh3 hash
+    #[default]
+    Hash,
+    /// Byte-for-byte comparison
+    Bytes,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CompareStats {
     pub identical: usize,
     pub modified: usize,
     pub left_only: usize,
     pub right_only: usize,
+    /// Only counted when comparing metadata
+    pub metadata_only: usize,
     pub total_files: usize,
 }
 
+/// What a scanned entry is on disk
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Dir,
+    /// A link that wasn't followed (not following links, broken, or a loop)
+    Symlink,
+    /// FIFOs, sockets, devices
+    Other,
+}
+
 // Simple directory entry for single-dir scan
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DirEntry {
@@ -194,9 +563,67 @@ pub struct DirEntry {
     pub rel_path: String,
     pub is_dir: bool,
     pub size: u64,
+    pub kind: EntryKind,
+    /// Where the entry points, if it's a symlink (followed or not)
+    pub link_target: Option<String>,
+    /// Files and directories only, when the scan compares metadata
+    pub metadata: Option<FileMetadata>,
     pub children: Vec<DirEntry>,
 }
 
+impl DirEntry {
+    /// Report a directory link that would loop as an unfollowed link
+    fn mark_loop(&mut self) {
+        self.kind = EntryKind::Symlink;
+        self.is_dir = false;
+    }
+}
+
+/// Permissions, modification time and ownership of a scanned entry
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileMetadata {
+    /// Permission bits (Unix only)
+    pub mode: Option<u32>,
+    /// Seconds since the Unix epoch
+    pub mtime: Option<u64>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl FileMetadata {
+    fn from_metadata(meta: &fs::Metadata) -> Self {
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            FileMetadata {
+                mode: Some(meta.mode() & 0o7777),
+                mtime,
+                uid: Some(meta.uid()),
+                gid: Some(meta.gid()),
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            FileMetadata { mode: None, mtime, uid: None, gid: None }
+        }
+    }
+
+    /// Whether two entries of the same kind differ in metadata. A directory's
+    /// mtime changes with its contents, so only files compare it.
+    fn differs(&self, other: &FileMetadata, is_dir: bool) -> bool {
+        self.mode != other.mode
+            || self.uid != other.uid
+            || self.gid != other.gid
+            || (!is_dir && self.mtime != other.mtime)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ScanResult {
     pub root_path: String,
@@ -212,6 +639,15 @@ pub enum EntryStatus {
     Modified,
     LeftOnly,
     RightOnly,
+    /// Same content, but mode bits, mtime or ownership differ
+    MetadataOnly,
+}
+
+impl EntryStatus {
+    /// Added, removed or modified, as opposed to a match or metadata-only difference
+    fn is_content_change(&self) -> bool {
+        matches!(self, EntryStatus::Modified | EntryStatus::LeftOnly | EntryStatus::RightOnly)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -221,6 +657,12 @@ pub struct AlignedEntry {
     pub is_dir: bool,
     pub left_size: Option<u64>,
     pub right_size: Option<u64>,
+    pub left_kind: Option<EntryKind>,
+    pub right_kind: Option<EntryKind>,
+    pub left_link_target: Option<String>,
+    pub right_link_target: Option<String>,
+    pub left_metadata: Option<FileMetadata>,
+    pub right_metadata: Option<FileMetadata>,
     pub status: EntryStatus,
     pub children: Vec<AlignedEntry>,
 }
@@ -240,6 +682,170 @@ pub struct ScanProgress {
     pub message: String,
 }
 
+// Three-way directory merge types
+
+/// Where an entry stands in a three-way directory merge, relative to base
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MergeState {
+    Unchanged,
+    ChangedLocal,
+    ChangedRemote,
+    /// Changed on both sides; `has_conflict` tells whether the changes clash
+    ChangedBoth,
+    AddedLocal,
+    AddedRemote,
+    /// Added on both sides with the same content
+    AddedBoth,
+    DeletedLocal,
+    DeletedRemote,
+    DeletedBoth,
+    /// Added on both sides with different content
+    AddAddConflict,
+    /// Changed on one side, deleted on the other
+    ModifyDeleteConflict,
+}
+
+impl MergeState {
+    /// The state of an entry present on all three sides
+    fn from_sides(local: bool, remote: bool) -> Self {
+        match (local, remote) {
+            (false, false) => MergeState::Unchanged,
+            (true, false) => MergeState::ChangedLocal,
+            (false, true) => MergeState::ChangedRemote,
+            (true, true) => MergeState::ChangedBoth,
+        }
+    }
+
+    /// Which sides changed the entry: (local, remote)
+    fn sides(&self) -> (bool, bool) {
+        match self {
+            MergeState::Unchanged => (false, false),
+            MergeState::ChangedLocal | MergeState::AddedLocal | MergeState::DeletedLocal => (true, false),
+            MergeState::ChangedRemote | MergeState::AddedRemote | MergeState::DeletedRemote => (false, true),
+            _ => (true, true),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MergeEntry {
+    pub name: String,
+    pub rel_path: String,
+    pub is_dir: bool,
+    pub local_kind: Option<EntryKind>,
+    pub base_kind: Option<EntryKind>,
+    pub remote_kind: Option<EntryKind>,
+    pub local_size: Option<u64>,
+    pub base_size: Option<u64>,
+    pub remote_size: Option<u64>,
+    pub state: MergeState,
+    /// Conflicting regions left by the automatic merge of a text file changed on both sides
+    pub conflict_count: Option<usize>,
+    /// Needs a manual decision; for directories, whether anything inside does
+    pub has_conflict: bool,
+    pub children: Vec<MergeEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MergeStats {
+    pub unchanged: usize,
+    /// Changed, added or deleted on one side only
+    pub local_changes: usize,
+    pub remote_changes: usize,
+    /// Touched on both sides, whether or not the changes clash
+    pub both_changes: usize,
+    pub conflicts: usize,
+    pub total_files: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DirMergeResult {
+    pub root_local: String,
+    pub root_base: String,
+    pub root_remote: String,
+    pub entries: Vec<MergeEntry>,
+    pub stats: MergeStats,
+}
+
+// Directory sync types
+
+/// What a sync operation does with an entry
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyncAction {
+    CopyToRight,
+    CopyToLeft,
+    DeleteLeft,
+    DeleteRight,
+}
+
+/// An entry picked in the directory view, by its aligned `rel_path`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncItem {
+    pub rel_path: String,
+    pub action: SyncAction,
+}
+
+/// One step of a sync plan
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncOperation {
+    pub rel_path: String,
+    pub action: SyncAction,
+    pub is_dir: bool,
+    /// Whether a copy writes over something already at the target.
+    /// Directories are merged, keeping entries only the target has.
+    pub replaces: bool,
+    /// Bytes copied, or removed for deletes
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncItemResult {
+    pub rel_path: String,
+    pub action: SyncAction,
+    /// `None` when the operation succeeded
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncReport {
+    pub root_left: String,
+    pub root_right: String,
+    pub dry_run: bool,
+    pub operations: Vec<SyncOperation>,
+    /// One per operation, in order; empty for a dry run
+    pub results: Vec<SyncItemResult>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncProgress {
+    pub done: usize,
+    pub total: usize,
+    pub rel_path: String,
+}
+
+// App-managed trash types
+
+/// An entry moved to the trash by `delete_entries`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashedEntry {
+    pub rel_path: String,
+    pub is_dir: bool,
+    /// Where it lives in the trash until restored or purged
+    pub trashed_path: String,
+}
+
+/// Entries removed together from one root; `undo_delete(id)` restores them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashBatch {
+    pub id: String,
+    pub root: String,
+    /// Seconds since the Unix epoch
+    pub created: u64,
+    pub entries: Vec<TrashedEntry>,
+}
+
 /// Check if content is binary by looking for null bytes in first 8KB
 fn is_binary(bytes: &[u8]) -> bool {
     let check_len = bytes.len().min(8192);
@@ -282,6 +888,7 @@ fn read_file(path: &str) -> Result<FileContent, String> {
             line_count: 0,
             is_binary: false,
             exists: false,
+            version: None,
         });
     }
 
@@ -297,6 +904,8 @@ fn read_file(path: &str) -> Result<FileContent, String> {
         path, read_start.elapsed(), 
         (size as f64 / 1_000_000.0) / read_start.elapsed().as_secs_f64());
 
+    let version = Some(version_token(&metadata, &bytes));
+
     // Check for binary
     let is_binary = is_binary(&bytes);
 
@@ -310,6 +919,7 @@ fn read_file(path: &str) -> Result<FileContent, String> {
             line_count: 0,
             is_binary: true,
             exists: true,
+            version,
         });
     }
 
@@ -330,6 +940,7 @@ fn read_file(path: &str) -> Result<FileContent, String> {
         line_count,
         is_binary: false,
         exists: true,
+        version,
     })
 }
 
@@ -450,28 +1061,114 @@ async fn compute_image_diff_mask(left_path: String, right_path: String) -> Resul
     .map_err(|e| format!("Image diff task failed: {}", e))?
 }
 
+/// Opaque token for one version of a file: its mtime, size and content hash
+fn version_token(meta: &fs::Metadata, bytes: &[u8]) -> String {
+    let mtime_ns = FileStamp::from_metadata(meta).map_or(0, |stamp| stamp.mtime_ns);
+    format!("{}-{}-{:016x}", mtime_ns, bytes.len(), xxhash_rust::xxh3::xxh3_64(bytes))
+}
+
+/// The version token of the file at `path` now, if it exists
+fn current_version(path: &Path) -> Option<String> {
+    let meta = fs::metadata(path).ok()?;
+    let bytes = fs::read(path).ok()?;
+    Some(version_token(&meta, &bytes))
+}
+
+/// Write `bytes` to `path` so that a crash leaves either the old file or the
+/// new one, never a truncated mix: the data goes to a temp file in the same
+/// directory, is flushed to disk, takes over the original's permissions (and
+/// ownership, where allowed) and is then renamed over the target.
+/// A symlinked target is written through, leaving the link in place.
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    use std::io::Write;
+
+    let target = match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_symlink() => {
+            fs::canonicalize(path).map_err(|e| format!("Failed to resolve {}: {}", path.display(), e))?
+        }
+        _ => path.to_path_buf(),
+    };
+    let dir = match target.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let name = target
+        .file_name()
+        .ok_or_else(|| format!("Invalid file path: {}", path.display()))?
+        .to_string_lossy();
+    let original = fs::metadata(&target).ok();
+
+    // Unique per call, so concurrent saves of one file don't share a temp file
+    static SAVE_COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    let seq = SAVE_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let tmp = dir.join(format!(".{}.diffvibe-{}-{}.tmp", name, std::process::id(), seq));
+    let write = || -> std::io::Result<()> {
+        let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).open(&tmp)?;
+        file.write_all(bytes)?;
+        if let Some(meta) = &original {
+            // Ownership first: chown clears the setuid and setgid bits
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+                // Only root can give a file away; other users keep their own ownership
+                if let Err(e) = std::os::unix::fs::fchown(&file, Some(meta.uid()), Some(meta.gid())) {
+                    debug!("Could not keep ownership of {}: {}", target.display(), e);
+                }
+            }
+            file.set_permissions(meta.permissions())?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, &target)?;
+        // Make the rename itself durable
+        #[cfg(unix)]
+        fs::File::open(&dir)?.sync_all()?;
+        Ok(())
+    };
+    write().map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        format!("Failed to write {}: {}", path.display(), e)
+    })
+}
+
+/// Save a file atomically in its original encoding, keeping the previous
+/// version as `<path>.bak` unless `backup` is false. With `expected_version` (from
+/// `read_file`), the save is refused if the file has changed since.
+/// Returns the version token of the saved file.
 #[tauri::command]
-fn write_file(path: &str, content: &str, encoding: &str) -> Result<(), String> {
+fn write_file(
+    path: &str,
+    content: &str,
+    encoding: &str,
+    backup: Option<bool>,
+    expected_version: Option<String>,
+) -> Result<String, WriteError> {
     let file_path = Path::new(path);
 
+    if let Some(expected) = expected_version {
+        let actual = current_version(file_path);
+        if actual.as_deref() != Some(expected.as_str()) {
+            info!("write_file: {} was modified on disk, not saving", path);
+            return Err(WriteError::ModifiedOnDisk { path: path.to_string(), expected, actual });
+        }
+    }
+
     // Create parent directories if needed
     if let Some(parent) = file_path.parent() {
-        if !parent.exists() {
+        if !parent.as_os_str().is_empty() && !parent.exists() {
             fs::create_dir_all(parent).map_err(|e| format!("Failed to create directory: {}", e))?;
         }
     }
 
-    // Create .bak backup if file exists
-    if file_path.exists() {
+    if backup.unwrap_or(true) && file_path.exists() {
         let backup_path = format!("{}.bak", path);
         fs::copy(file_path, &backup_path).map_err(|e| format!("Failed to create backup: {}", e))?;
     }
 
     // Encode content and write
     let bytes = encode_content(content, encoding);
-    fs::write(file_path, bytes).map_err(|e| format!("Failed to write file: {}", e))?;
-
-    Ok(())
+    write_atomic(file_path, &bytes)?;
+    let meta = fs::metadata(file_path).map_err(|e| format!("Failed to read back {}: {}", path, e))?;
+    Ok(version_token(&meta, &bytes))
 }
 
 #[tauri::command]
@@ -511,569 +1208,1482 @@ fn is_directory(path: &str) -> Result<bool, String> {
     Ok(Path::new(path).is_dir())
 }
 
-#[tauri::command]
-fn compute_diff(left: &str, right: &str) -> DiffResult {
-    let start = Instant::now();
-    let left_lines = left.lines().count();
-    let right_lines = right.lines().count();
-    info!("compute_diff: starting - left={} lines, right={} lines", left_lines, right_lines);
-    
-    let diff_start = Instant::now();
-    let diff = TextDiff::from_lines(left, right);
-    info!("compute_diff: TextDiff::from_lines took {:?}", diff_start.elapsed());
-    
-    let iter_start = Instant::now();
-    let mut lines = Vec::new();
-    let mut additions = 0;
-    let mut deletions = 0;
-    let mut unchanged = 0;
+/// Upper bound on how often a line may occur in old and still anchor a
+/// histogram split (same limit as git)
+const HISTOGRAM_MAX_CHAIN: usize = 64;
+
+/// Histogram diff of `old[old_range]` vs `new[new_range]` into `hook`.
+/// Splits around the matching run that starts at the least frequent common
+/// line; regions without a usable anchor fall back to Myers.
+fn histogram_diff<T: Eq + Hash>(
+    hook: &mut Capture,
+    old: &[T],
+    mut old_range: std::ops::Range<usize>,
+    new: &[T],
+    mut new_range: std::ops::Range<usize>,
+    deadline: Option<Instant>,
+) {
+    loop {
+        // Common prefix
+        let prefix = old[old_range.clone()]
+            .iter()
+            .zip(&new[new_range.clone()])
+            .take_while(|(a, b)| a == b)
+            .count();
+        if prefix > 0 {
+            hook.equal(old_range.start, new_range.start, prefix).ok();
+            old_range.start += prefix;
+            new_range.start += prefix;
+        }
 
-    for change in diff.iter_all_changes() {
-        let (tag, old_index, new_index) = match change.tag() {
-            ChangeTag::Equal => {
-                unchanged += 1;
-                ("equal", change.old_index(), change.new_index())
+        // Common suffix, emitted after the middle
+        let suffix = old[old_range.clone()]
+            .iter()
+            .rev()
+            .zip(new[new_range.clone()].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        old_range.end -= suffix;
+        new_range.end -= suffix;
+        let suffix_at = (old_range.end, new_range.end);
+
+        let timed_out = deadline.is_some_and(|d| Instant::now() >= d);
+        if old_range.is_empty() || new_range.is_empty() || timed_out {
+            if !old_range.is_empty() {
+                hook.delete(old_range.start, old_range.len(), new_range.start).ok();
             }
-            ChangeTag::Insert => {
-                additions += 1;
-                ("insert", None, change.new_index())
+            if !new_range.is_empty() {
+                hook.insert(old_range.start, new_range.start, new_range.len()).ok();
             }
-            ChangeTag::Delete => {
-                deletions += 1;
-                ("delete", change.old_index(), None)
+        } else {
+            let mut counts: HashMap<&T, (usize, usize)> = HashMap::new();
+            for i in old_range.clone() {
+                counts.entry(&old[i]).or_insert((0, i)).0 += 1;
             }
-        };
 
-        lines.push(DiffLine {
-            tag: tag.to_string(),
-            old_index,
-            new_index,
-            value: change.value().to_string(),
-        });
-    }
-    
-    info!("compute_diff: iteration took {:?}, generated {} diff lines", 
-        iter_start.elapsed(), lines.len());
-    info!("compute_diff: complete - +{} -{} ={}, total time {:?}", 
-        additions, deletions, unchanged, start.elapsed());
+            // Least frequent line of old that also appears in new
+            let anchor = new_range
+                .clone()
+                .filter_map(|j| counts.get(&new[j]).map(|&(count, i)| (count, i, j)))
+                .filter(|&(count, _, _)| count <= HISTOGRAM_MAX_CHAIN)
+                .min_by_key(|&(count, _, _)| count);
+
+            match anchor {
+                Some((_, i, j)) => {
+                    let len = old[i..old_range.end]
+                        .iter()
+                        .zip(&new[j..new_range.end])
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    histogram_diff(hook, old, old_range.start..i, new, new_range.start..j, deadline);
+                    hook.equal(i, j, len).ok();
+                    // Continue with the part after the anchor run
+                    old_range.start = i + len;
+                    new_range.start = j + len;
+                    old_range.end += suffix;
+                    new_range.end += suffix;
+                    continue;
+                }
+                None => {
+                    similar::algorithms::myers::diff_deadline(hook, old, old_range, new, new_range, deadline).ok();
+                }
+            }
+        }
 
-    DiffResult {
-        lines,
-        stats: DiffStats {
-            additions,
-            deletions,
-            unchanged,
-        },
+        if suffix > 0 {
+            hook.equal(suffix_at.0, suffix_at.1, suffix).ok();
+        }
+        return;
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct FileDiffResult {
-    pub left: FileContent,
-    pub right: FileContent,
-    pub diff: DiffResult,
+/// Diff two slices with the chosen algorithm and optional time budget
+fn diff_ops<T: Eq + Hash + Ord>(old: &[T], new: &[T], algorithm: DiffAlgorithm, timeout_ms: Option<u64>) -> Vec<DiffOp> {
+    let deadline = timeout_ms.map(|ms| Instant::now() + Duration::from_millis(ms));
+    let algorithm = match algorithm {
+        DiffAlgorithm::Myers => similar::Algorithm::Myers,
+        DiffAlgorithm::Patience => similar::Algorithm::Patience,
+        DiffAlgorithm::Lcs => similar::Algorithm::Lcs,
+        DiffAlgorithm::Histogram => {
+            let mut capture = Capture::new();
+            histogram_diff(&mut capture, old, 0..old.len(), new, 0..new.len(), deadline);
+            return capture.into_ops();
+        }
+    };
+    similar::capture_diff_slices_deadline(algorithm, old, new, deadline)
 }
 
-#[tauri::command]
-fn compute_diff_files(left_path: &str, right_path: &str) -> Result<FileDiffResult, String> {
-    let start = Instant::now();
-    info!("compute_diff_files: {} vs {}", left_path, right_path);
-    
-    let left = read_file(left_path)?;
-    let right = read_file(right_path)?;
+/// Comparison key for a line under the given options. Without whitespace
+/// options the line ending is part of the key, matching a plain line diff.
+fn normalize_line<'a>(line: &'a str, options: &DiffOptions) -> Cow<'a, str> {
+    let mut key = Cow::Borrowed(line);
+
+    if options.ignore_all_whitespace {
+        key = Cow::Owned(key.chars().filter(|c| !c.is_whitespace()).collect());
+    } else if options.ignore_whitespace_amount {
+        // Collapse whitespace runs to one space and drop trailing whitespace
+        key = Cow::Owned(key.split_whitespace().collect::<Vec<_>>().join(" "));
+        if line.starts_with(char::is_whitespace) && !key.is_empty() {
+            key = Cow::Owned(format!(" {}", key));
+        }
+    } else if options.ignore_trailing_whitespace {
+        key = Cow::Borrowed(line.trim_end());
+    }
 
-    // If either is binary, return empty diff
-    if left.is_binary || right.is_binary {
-        info!("compute_diff_files: binary file detected, skipping diff");
-        return Ok(FileDiffResult {
-            left,
-            right,
-            diff: DiffResult {
-                lines: vec![],
-                stats: DiffStats {
-                    additions: 0,
-                    deletions: 0,
-                    unchanged: 0,
-                },
-            },
-        });
+    if options.ignore_case {
+        key = Cow::Owned(key.to_lowercase());
     }
 
-    let diff = compute_diff(&left.content, &right.content);
-    
-    info!("compute_diff_files: complete, total time {:?}", start.elapsed());
+    key
+}
 
-    Ok(FileDiffResult { left, right, diff })
+/// Flag changed runs made up only of blank lines as ignored, like `diff -B`.
+/// They keep their tags; a run with any other line stays a real change.
+fn mark_blank_hunks(lines: &mut [DiffLine]) {
+    let mut i = 0;
+    while i < lines.len() {
+        let start = i;
+        while i < lines.len() && lines[i].tag != "equal" {
+            i += 1;
+        }
+        if lines[start..i].iter().all(|l| l.value.trim().is_empty()) {
+            lines[start..i].iter_mut().for_each(|l| l.ignored = true);
+        }
+        i = i.max(start + 1);
+    }
 }
 
-/// Three-way merge using diff operations from the similar crate
-/// Walks through diff ops for base→local and base→remote in parallel
-#[tauri::command]
-fn compute_three_way_diff(base: &str, local: &str, remote: &str) -> MergeResult {
-    use similar::{ChangeTag, TextDiff};
+/// Line diff of two tokenized texts. Lines are compared by their normalized
+/// keys; with ignore_blank_lines, hunks of only blank lines are flagged ignored.
+fn diff_lines(old_lines: &[&str], new_lines: &[&str], options: &DiffOptions) -> Vec<DiffLine> {
+    let old_keys: Vec<Cow<str>> = old_lines.iter().map(|l| normalize_line(l, options)).collect();
+    let new_keys: Vec<Cow<str>> = new_lines.iter().map(|l| normalize_line(l, options)).collect();
+    let ops = diff_ops(&old_keys, &new_keys, options.algorithm, options.timeout_ms);
 
-    // Get changes from base to each branch
-    let local_diff = TextDiff::from_lines(base, local);
-    let remote_diff = TextDiff::from_lines(base, remote);
+    // Iterate over indices rather than keys, which may be owned strings
+    let old_idx: Vec<usize> = (0..old_lines.len()).collect();
+    let new_idx: Vec<usize> = (0..new_lines.len()).collect();
 
-    // Collect all changes with their base line indices
-    let local_changes: Vec<_> = local_diff.iter_all_changes().collect();
-    let remote_changes: Vec<_> = remote_diff.iter_all_changes().collect();
+    let mut lines = Vec::new();
+    for change in ops.iter().flat_map(|op| op.iter_changes(&old_idx, &new_idx)) {
+        let (old_index, new_index) = (change.old_index(), change.new_index());
+        let line = match (change.tag(), old_index, new_index) {
+            (ChangeTag::Equal, Some(o), Some(n)) => {
+                let mut line = DiffLine::new("equal", old_index, new_index, old_lines[o]);
+                // Ignore options can pair lines whose text differs
+                line.new_value = (new_lines[n] != old_lines[o]).then(|| new_lines[n].to_string());
+                line
+            }
+            (ChangeTag::Insert, _, Some(n)) => DiffLine::new("insert", None, new_index, new_lines[n]),
+            (_, Some(o), _) => DiffLine::new("delete", old_index, None, old_lines[o]),
+            _ => continue,
+        };
+        lines.push(line);
+    }
+    if options.ignore_blank_lines {
+        mark_blank_hunks(&mut lines);
+    }
 
-    let chunks: Vec<MergeChunk> = Vec::new();
-    let mut conflict_count = 0;
-    let mut merged_lines: Vec<String> = Vec::new();
+    lines
+}
 
-    let base_lines: Vec<&str> = base.lines().collect();
+/// Lines longer than this (in bytes) get no inline spans, to keep
+/// pathological inputs (minified files) fast
+const MAX_INLINE_LINE_LEN: usize = 10_000;
 
-    // Track insertions AFTER each base line index
-    // Key = base_idx means "insert these lines after processing base line base_idx"
-    let mut local_inserts: std::collections::HashMap<usize, Vec<String>> = std::collections::HashMap::new();
-    let mut remote_inserts: std::collections::HashMap<usize, Vec<String>> = std::collections::HashMap::new();
-    let mut local_deletes: std::collections::HashSet<usize> = std::collections::HashSet::new();
-    let mut remote_deletes: std::collections::HashSet<usize> = std::collections::HashSet::new();
+/// Changed ranges of `old` and `new` at the given granularity (word or
+/// char), as UTF-16 offsets with adjacent ranges merged
+fn inline_spans<'a>(diff: &TextDiff<'a, 'a, '_, str>) -> (Vec<InlineSpan>, Vec<InlineSpan>) {
+    let mut old_spans: Vec<InlineSpan> = Vec::new();
+    let mut new_spans: Vec<InlineSpan> = Vec::new();
+    let (mut old_pos, mut new_pos) = (0usize, 0usize);
+
+    let push = |spans: &mut Vec<InlineSpan>, start: usize, end: usize| match spans.last_mut() {
+        Some(last) if last.end == start => last.end = end,
+        _ => spans.push(InlineSpan { start, end }),
+    };
 
-    // Process local changes - track where inserts go
-    let mut base_idx = 0usize;
-    for change in &local_changes {
+    for change in diff.iter_all_changes() {
+        let len = change.value().encode_utf16().count();
         match change.tag() {
             ChangeTag::Equal => {
-                base_idx += 1;
+                old_pos += len;
+                new_pos += len;
             }
             ChangeTag::Delete => {
-                local_deletes.insert(base_idx);
-                base_idx += 1;
+                push(&mut old_spans, old_pos, old_pos + len);
+                old_pos += len;
             }
             ChangeTag::Insert => {
-                // Insert goes at current base position (before the next base line)
-                local_inserts.entry(base_idx).or_default().push(change.value().to_string());
+                push(&mut new_spans, new_pos, new_pos + len);
+                new_pos += len;
             }
         }
     }
 
-    // Process remote changes
-    base_idx = 0;
-    for change in &remote_changes {
-        match change.tag() {
-            ChangeTag::Equal => {
-                base_idx += 1;
-            }
-            ChangeTag::Delete => {
-                remote_deletes.insert(base_idx);
-                base_idx += 1;
+    (old_spans, new_spans)
+}
+
+/// Pair each run of deleted lines with the inserted lines that follow it and
+/// attach word- and char-level spans of what changed within each pair.
+/// Pairs left at `deadline` get no spans.
+fn add_inline_spans(lines: &mut [DiffLine], deadline: Option<Instant>) {
+    let configure = || {
+        let mut config = TextDiff::configure();
+        if let Some(deadline) = deadline {
+            config.deadline(deadline);
+        }
+        config
+    };
+
+    let mut i = 0;
+    while i < lines.len() {
+        let del_start = i;
+        while i < lines.len() && lines[i].tag == "delete" {
+            i += 1;
+        }
+        let ins_start = i;
+        while i < lines.len() && lines[i].tag == "insert" {
+            i += 1;
+        }
+        if del_start == ins_start {
+            i += 1;
+            continue;
+        }
+
+        let pairs = (ins_start - del_start).min(i - ins_start);
+        for k in 0..pairs {
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                return;
             }
-            ChangeTag::Insert => {
-                remote_inserts.entry(base_idx).or_default().push(change.value().to_string());
+            let (old_idx, new_idx) = (del_start + k, ins_start + k);
+            // Compare without line endings so a missing final newline isn't emphasized
+            let old = lines[old_idx].value.trim_end_matches(['\n', '\r']).to_string();
+            let new = lines[new_idx].value.trim_end_matches(['\n', '\r']).to_string();
+            if old.len() > MAX_INLINE_LINE_LEN || new.len() > MAX_INLINE_LINE_LEN {
+                continue;
             }
+
+            let (old_words, new_words) = inline_spans(&configure().diff_words(&old, &new));
+            let (old_chars, new_chars) = inline_spans(&configure().diff_chars(&old, &new));
+            lines[old_idx].word_spans = old_words;
+            lines[old_idx].char_spans = old_chars;
+            lines[new_idx].word_spans = new_words;
+            lines[new_idx].char_spans = new_chars;
         }
     }
+}
 
-    // Helper to add insertions at a given position
-    let add_insertions = |pos: usize, merged: &mut Vec<String>, conflicts: &mut usize| {
-        let local_ins = local_inserts.get(&pos);
-        let remote_ins = remote_inserts.get(&pos);
+/// Minimum size of a moved block, so stray `}` or blank lines don't count as
+/// moves: at least this many lines and this many alphanumeric characters
+const MIN_MOVED_LINES: usize = 3;
+const MIN_MOVED_CHARS: usize = 20;
+/// Lines inserted more often than this (braces, blank-ish boilerplate) can't
+/// anchor a move; skipping them keeps detection linear
+const MAX_MOVE_CANDIDATES: usize = 8;
+
+/// Find blocks of deleted lines that were inserted unchanged (ignoring
+/// whitespace) in another hunk, link their lines through `move_index`, and
+/// return the blocks. Stops with what it has found at `deadline`.
+fn detect_moved_blocks(lines: &mut [DiffLine], deadline: Option<Instant>) -> Vec<MovedBlock> {
+    let keys: Vec<String> = lines
+        .iter()
+        .map(|l| l.value.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect();
 
-        match (local_ins, remote_ins) {
-            (Some(l_lines), Some(r_lines)) => {
-                if l_lines == r_lines {
-                    for line in l_lines {
-                        merged.push(line.trim_end().to_string());
-                    }
-                } else {
-                    *conflicts += 1;
-                    merged.push("<<<<<<< LOCAL".to_string());
-                    for line in l_lines {
-                        merged.push(line.trim_end().to_string());
-                    }
-                    merged.push("=======".to_string());
-                    for line in r_lines {
-                        merged.push(line.trim_end().to_string());
-                    }
-                    merged.push(">>>>>>> REMOTE".to_string());
-                }
-            }
-            (Some(l_lines), None) => {
-                for line in l_lines {
-                    merged.push(line.trim_end().to_string());
-                }
+    // Number each run of changed lines; a "move" inside one hunk is just an edit
+    let mut hunk_ids = vec![0usize; lines.len()];
+    let mut hunk = 0;
+    for (i, line) in lines.iter().enumerate() {
+        if line.tag == "equal" {
+            continue;
+        }
+        if i == 0 || lines[i - 1].tag == "equal" {
+            hunk += 1;
+        }
+        hunk_ids[i] = hunk;
+    }
+
+    let mut deleted: HashMap<usize, usize> = HashMap::new(); // old index -> position
+    let mut inserted: HashMap<usize, usize> = HashMap::new(); // new index -> position
+    let mut by_key: HashMap<&str, Vec<usize>> = HashMap::new(); // key -> new indices
+    for (pos, line) in lines.iter().enumerate() {
+        match (line.tag.as_str(), line.old_index, line.new_index) {
+            ("delete", Some(old), _) => {
+                deleted.insert(old, pos);
             }
-            (None, Some(r_lines)) => {
-                for line in r_lines {
-                    merged.push(line.trim_end().to_string());
-                }
+            ("insert", _, Some(new)) => {
+                inserted.insert(new, pos);
+                by_key.entry(keys[pos].as_str()).or_default().push(new);
             }
-            (None, None) => {}
+            _ => {}
         }
-    };
+    }
 
-    // Walk through base lines
-    for (i, base_line) in base_lines.iter().enumerate() {
-        // First, add any insertions BEFORE this base line
-        add_insertions(i, &mut merged_lines, &mut conflict_count);
+    let mut old_starts: Vec<usize> = deleted.keys().copied().collect();
+    old_starts.sort_unstable();
 
-        let local_deleted = local_deletes.contains(&i);
-        let remote_deleted = remote_deletes.contains(&i);
+    let mut moved = vec![false; lines.len()];
+    let mut blocks: Vec<MovedBlock> = Vec::new();
 
-        // Handle the base line itself
-        match (local_deleted, remote_deleted) {
-            (true, true) => {
-                // Both deleted - don't include
-            }
-            (true, false) => {
-                // Local deleted, remote kept - take local's deletion (skip line)
+    for old_start in old_starts {
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            debug!("detect_moved_blocks: deadline reached after {} blocks", blocks.len());
+            break;
+        }
+        let pos = deleted[&old_start];
+        if moved[pos] || keys[pos].is_empty() {
+            continue;
+        }
+        let candidates = by_key.get(keys[pos].as_str()).map_or(&[][..], |c| c.as_slice());
+        if candidates.len() > MAX_MOVE_CANDIDATES {
+            continue;
+        }
+
+        // Longest run of matching lines starting here, over all destinations
+        let mut best: Option<(usize, usize)> = None;
+        for &new_start in candidates {
+            if hunk_ids[inserted[&new_start]] == hunk_ids[pos] {
+                continue;
             }
-            (false, true) => {
-                // Remote deleted, local kept - take remote's deletion (skip line)
+            let mut len = 0;
+            while let (Some(&dp), Some(&ip)) = (deleted.get(&(old_start + len)), inserted.get(&(new_start + len))) {
+                if moved[dp] || moved[ip] || keys[dp] != keys[ip] || hunk_ids[dp] == hunk_ids[ip] {
+                    break;
+                }
+                len += 1;
             }
-            (false, false) => {
-                // Both kept - include the line
-                merged_lines.push(base_line.to_string());
+            if len > best.map_or(0, |(_, l)| l) {
+                best = Some((new_start, len));
             }
         }
-    }
 
-    // Add any insertions after the last base line
-    add_insertions(base_lines.len(), &mut merged_lines, &mut conflict_count);
+        let Some((new_start, count)) = best else { continue };
+        let pairs: Vec<(usize, usize)> = (0..count)
+            .map(|k| (deleted[&(old_start + k)], inserted[&(new_start + k)]))
+            .collect();
+        let alnum: usize = pairs
+            .iter()
+            .map(|&(dp, _)| keys[dp].chars().filter(|c| c.is_alphanumeric()).count())
+            .sum();
+        if count < MIN_MOVED_LINES || alnum < MIN_MOVED_CHARS {
+            continue;
+        }
 
-    MergeResult {
-        chunks,
-        conflict_count,
-        merged_content: merged_lines.join("\n"),
+        let move_index = blocks.len();
+        let mut exact = true;
+        for (dp, ip) in pairs {
+            moved[dp] = true;
+            moved[ip] = true;
+            lines[dp].move_index = Some(move_index);
+            lines[ip].move_index = Some(move_index);
+            exact &= lines[dp].value == lines[ip].value;
+        }
+        blocks.push(MovedBlock {
+            old_start,
+            new_start,
+            count,
+            exact,
+        });
     }
+
+    blocks
 }
 
-/// Compute file hash for comparison (first 64KB for speed)
-fn file_hash(path: &Path) -> Option<u64> {
-    let bytes = fs::read(path).ok()?;
-    let check_len = bytes.len().min(65536);
-    Some(xxh3_64(&bytes[..check_len]))
+#[tauri::command]
+fn compute_diff(left: &str, right: &str, options: Option<DiffOptions>) -> DiffResult {
+    let start = Instant::now();
+    let options = options.unwrap_or_default();
+    let old_lines = left.tokenize_lines();
+    let new_lines = right.tokenize_lines();
+    info!("compute_diff: starting - left={} lines, right={} lines", old_lines.len(), new_lines.len());
+
+    // Diff normalized keys, but report the original lines and indices
+    let diff_start = Instant::now();
+    let mut lines = diff_lines(&old_lines, &new_lines, &options);
+    info!("compute_diff: line diff took {:?}, generated {} diff lines", diff_start.elapsed(), lines.len());
+
+    // Inline spans and move detection share the diff's time budget
+    let deadline = options.timeout_ms.map(|ms| start + Duration::from_millis(ms));
+    let inline_start = Instant::now();
+    add_inline_spans(&mut lines, deadline);
+    let moves = detect_moved_blocks(&mut lines, deadline);
+    info!("compute_diff: inline spans and {} moved blocks took {:?}", moves.len(), inline_start.elapsed());
+
+    let additions = lines.iter().filter(|l| l.tag == "insert" && !l.ignored).count();
+    let deletions = lines.iter().filter(|l| l.tag == "delete" && !l.ignored).count();
+    let unchanged = lines.len() - additions - deletions;
+    
+    info!("compute_diff: complete - +{} -{} ={}, total time {:?}", 
+        additions, deletions, unchanged, start.elapsed());
+
+    DiffResult {
+        lines,
+        stats: DiffStats {
+            additions,
+            deletions,
+            unchanged,
+        },
+        moves,
+    }
 }
 
-/// Scan a directory and build a map of rel_path -> (size, hash)
-fn scan_dir_entries(root: &Path) -> Result<HashMap<String, (u64, Option<u64>)>, String> {
-    let mut entries = HashMap::new();
-    scan_dir_recursive(root, root, &mut entries)?;
-    Ok(entries)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileDiffResult {
+    pub left: FileContent,
+    pub right: FileContent,
+    pub diff: DiffResult,
 }
 
-fn scan_dir_recursive(
-    root: &Path,
-    current: &Path,
-    entries: &mut HashMap<String, (u64, Option<u64>)>,
-) -> Result<(), String> {
-    let read_dir = fs::read_dir(current).map_err(|e| format!("Failed to read {:?}: {}", current, e))?;
+#[tauri::command]
+fn compute_diff_files(left_path: &str, right_path: &str, options: Option<DiffOptions>) -> Result<FileDiffResult, String> {
+    let start = Instant::now();
+    info!("compute_diff_files: {} vs {}", left_path, right_path);
+    
+    let left = read_file(left_path)?;
+    let right = read_file(right_path)?;
 
-    for entry in read_dir {
-        let entry = entry.map_err(|e| e.to_string())?;
-        let path = entry.path();
-        let rel_path = path
-            .strip_prefix(root)
-            .map_err(|e| e.to_string())?
-            .to_string_lossy()
-            .to_string();
+    // If either is binary, return empty diff
+    if left.is_binary || right.is_binary {
+        info!("compute_diff_files: binary file detected, skipping diff");
+        return Ok(FileDiffResult {
+            left,
+            right,
+            diff: DiffResult {
+                lines: vec![],
+                stats: DiffStats {
+                    additions: 0,
+                    deletions: 0,
+                    unchanged: 0,
+                },
+                moves: vec![],
+            },
+        });
+    }
 
-        // Skip hidden files/dirs
-        if rel_path.starts_with('.') || rel_path.contains("/.") {
-            continue;
-        }
+    let diff = compute_diff(&left.content, &right.content, options);
+    
+    info!("compute_diff_files: complete, total time {:?}", start.elapsed());
 
-        if path.is_dir() {
-            scan_dir_recursive(root, &path, entries)?;
-        } else if path.is_file() {
-            let meta = fs::metadata(&path).map_err(|e| e.to_string())?;
-            let size = meta.len();
-            let hash = file_hash(&path);
-            entries.insert(rel_path, (size, hash));
+    Ok(FileDiffResult { left, right, diff })
+}
+
+/// Diff two files for headless output. Returns `None` when they are identical.
+fn unified_diff_files(
+    left_path: &str,
+    right_path: &str,
+    context: usize,
+    options: DiffOptions,
+) -> Result<Option<String>, String> {
+    for path in [left_path, right_path] {
+        if Path::new(path).is_dir() {
+            return Err(format!("{}: Is a directory", path));
+        }
+        if !Path::new(path).exists() {
+            return Err(format!("{}: No such file or directory", path));
         }
     }
 
-    Ok(())
-}
+    let result = compute_diff_files(left_path, right_path, Some(options))?;
 
-/// Build tree structure from flat comparison results
-fn build_compare_tree(
-    left_entries: &HashMap<String, (u64, Option<u64>)>,
-    right_entries: &HashMap<String, (u64, Option<u64>)>,
-    stats: &mut CompareStats,
-) -> Vec<CompareEntry> {
-    // Collect all unique paths and sort them
-    let mut all_paths: Vec<&String> = left_entries.keys().chain(right_entries.keys()).collect();
-    all_paths.sort();
-    all_paths.dedup();
-
-    // Group by directory structure
-    let mut root_entries: Vec<CompareEntry> = Vec::new();
-    let mut dir_map: HashMap<String, Vec<CompareEntry>> = HashMap::new();
+    // Binary files get no line diff, so compare the raw bytes instead
+    if result.left.is_binary || result.right.is_binary {
+        let left_bytes = fs::read(left_path).map_err(|e| format!("{}: {}", left_path, e))?;
+        let right_bytes = fs::read(right_path).map_err(|e| format!("{}: {}", right_path, e))?;
+        if left_bytes == right_bytes {
+            return Ok(None);
+        }
+        return Ok(Some(format!("Binary files {} and {} differ\n", left_path, right_path)));
+    }
 
-    for rel_path in all_paths {
-        let left = left_entries.get(rel_path);
-        let right = right_entries.get(rel_path);
+    let stats = &result.diff.stats;
+    if stats.additions == 0 && stats.deletions == 0 {
+        return Ok(None);
+    }
 
-        let status = match (left, right) {
-            (Some((_, lh)), Some((_, rh))) => {
-                if lh == rh {
-                    stats.identical += 1;
-                    FileStatus::Identical
-                } else {
-                    stats.modified += 1;
-                    FileStatus::Modified
-                }
-            }
-            (Some(_), None) => {
-                stats.left_only += 1;
-                FileStatus::LeftOnly
-            }
-            (None, Some(_)) => {
-                stats.right_only += 1;
-                FileStatus::RightOnly
-            }
-            (None, None) => continue,
-        };
+    Ok(Some(format_unified_diff(&result.diff, left_path, right_path, context)))
+}
 
-        stats.total_files += 1;
-
-        let name = Path::new(rel_path)
-            .file_name()
-            .map(|s| s.to_string_lossy().to_string())
-            .unwrap_or_else(|| rel_path.clone());
-
-        let entry = CompareEntry {
-            name,
-            rel_path: rel_path.clone(),
-            is_dir: false,
-            status,
-            left_size: left.map(|(s, _)| *s),
-            right_size: right.map(|(s, _)| *s),
-            children: Vec::new(),
-        };
+/// Format a hunk range for a unified diff header (`start,count`, 1-based).
+/// `before` is the number of lines preceding the hunk on that side.
+fn unified_range(before: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", before),
+        1 => format!("{}", before + 1),
+        _ => format!("{},{}", before + 1, count),
+    }
+}
 
-        // Get parent directory
-        let parent = Path::new(rel_path)
-            .parent()
-            .map(|p| p.to_string_lossy().to_string())
-            .unwrap_or_default();
+/// Render a diff result as a standard unified diff with `context` lines around each hunk
+fn format_unified_diff(diff: &DiffResult, left_label: &str, right_label: &str, context: usize) -> String {
+    let lines = &diff.lines;
 
-        if parent.is_empty() {
-            root_entries.push(entry);
-        } else {
-            dir_map.entry(parent).or_default().push(entry);
+    // Group changed lines into hunks, merging ones whose context overlaps
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        if line.tag == "equal" || line.ignored {
+            continue;
+        }
+        let start = i.saturating_sub(context);
+        let end = (i + 1 + context).min(lines.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
         }
     }
 
-    // Build directory entries and nest children
-    let mut all_dirs: Vec<String> = dir_map.keys().cloned().collect();
-    all_dirs.sort_by_key(|b| Reverse(b.matches('/').count())); // Deepest first
-
-    for dir_path in all_dirs {
-        if let Some(children) = dir_map.remove(&dir_path) {
-            let name = Path::new(&dir_path)
-                .file_name()
-                .map(|s| s.to_string_lossy().to_string())
-                .unwrap_or_else(|| dir_path.clone());
-
-            // Compute dir status from children
-            let status = compute_dir_status(&children);
+    if hunks.is_empty() {
+        return String::new();
+    }
 
-            let dir_entry = CompareEntry {
-                name,
-                rel_path: dir_path.clone(),
-                is_dir: true,
-                status,
-                left_size: None,
-                right_size: None,
-                children,
-            };
+    let mut out = format!("--- {}\n+++ {}\n", left_label, right_label);
+    let mut old_before = 0;
+    let mut new_before = 0;
+    let mut pos = 0;
 
-            let parent = Path::new(&dir_path)
-                .parent()
-                .map(|p| p.to_string_lossy().to_string())
-                .unwrap_or_default();
+    for (start, end) in hunks {
+        // Count by index: changed lines, ignored or not, exist on one side only
+        for line in &lines[pos..start] {
+            old_before += usize::from(line.old_index.is_some());
+            new_before += usize::from(line.new_index.is_some());
+        }
 
-            if parent.is_empty() {
-                root_entries.push(dir_entry);
-            } else {
-                dir_map.entry(parent).or_default().push(dir_entry);
+        let hunk = &lines[start..end];
+        let old_count = hunk.iter().filter(|l| l.old_index.is_some()).count();
+        let new_count = hunk.iter().filter(|l| l.new_index.is_some()).count();
+        out.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            unified_range(old_before, old_count),
+            unified_range(new_before, new_count)
+        ));
+
+        for line in hunk {
+            out.push(match (line.old_index, line.new_index) {
+                (Some(_), Some(_)) => ' ',
+                (Some(_), None) => '-',
+                _ => '+',
+            });
+            out.push_str(&line.value);
+            if !line.value.ends_with('\n') {
+                out.push_str("\n\\ No newline at end of file\n");
             }
         }
+
+        old_before += old_count;
+        new_before += new_count;
+        pos = end;
     }
 
-    // Sort entries: directories first, then by name
-    root_entries.sort_by(|a, b| {
-        match (a.is_dir, b.is_dir) {
-            (true, false) => std::cmp::Ordering::Less,
-            (false, true) => std::cmp::Ordering::Greater,
-            _ => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
-        }
-    });
+    out
+}
 
-    root_entries
+/// A change from base to one side: base lines `base` became side lines `side`
+#[derive(Debug, Clone)]
+struct MergeHunk {
+    base: std::ops::Range<usize>,
+    side: std::ops::Range<usize>,
 }
 
-fn compute_dir_status(children: &[CompareEntry]) -> FileStatus {
-    let mut has_modified = false;
-    let mut has_left_only = false;
-    let mut has_right_only = false;
+/// Collect the non-equal regions of a base→side line diff, joining adjacent
+/// delete/insert ops into a single replace hunk
+fn diff_hunks(base: &[&str], side: &[&str], algorithm: DiffAlgorithm) -> Vec<MergeHunk> {
+    let mut hunks: Vec<MergeHunk> = Vec::new();
 
-    for child in children {
-        match child.status {
-            FileStatus::Modified => has_modified = true,
-            FileStatus::LeftOnly => has_left_only = true,
-            FileStatus::RightOnly => has_right_only = true,
-            FileStatus::Identical => {}
+    for op in diff_ops(base, side, algorithm, None) {
+        if let DiffOp::Equal { .. } = op {
+            continue;
+        }
+        let (base_range, side_range) = (op.old_range(), op.new_range());
+        match hunks.last_mut() {
+            Some(last) if last.base.end == base_range.start && last.side.end == side_range.start => {
+                last.base.end = base_range.end;
+                last.side.end = side_range.end;
+            }
+            _ => hunks.push(MergeHunk { base: base_range, side: side_range }),
         }
     }
 
-    if has_modified {
-        FileStatus::Modified
-    } else if has_left_only && !has_right_only {
-        FileStatus::LeftOnly
-    } else if has_right_only && !has_left_only {
-        FileStatus::RightOnly
-    } else if has_left_only || has_right_only {
-        FileStatus::Modified // Mixed
-    } else {
-        FileStatus::Identical
-    }
+    hunks
 }
 
-#[tauri::command]
-fn compare_directories(left_path: &str, right_path: &str) -> Result<DirectoryCompareResult, String> {
-    let left = Path::new(left_path);
-    let right = Path::new(right_path);
+/// Reconstruct one side's version of base lines `start..end`, applying that
+/// side's hunks (which must all lie within the range)
+fn side_region(base: &[&str], side: &[&str], hunks: &[MergeHunk], start: usize, end: usize) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut pos = start;
 
-    if !left.is_dir() {
-        return Err(format!("{} is not a directory", left_path));
-    }
-    if !right.is_dir() {
-        return Err(format!("{} is not a directory", right_path));
+    for hunk in hunks {
+        lines.extend(base[pos..hunk.base.start].iter().map(|s| s.to_string()));
+        lines.extend(side[hunk.side.clone()].iter().map(|s| s.to_string()));
+        pos = hunk.base.end;
     }
+    lines.extend(base[pos..end].iter().map(|s| s.to_string()));
 
-    let left_entries = scan_dir_entries(left)?;
-    let right_entries = scan_dir_entries(right)?;
+    lines
+}
 
-    let mut stats = CompareStats {
-        identical: 0,
-        modified: 0,
-        left_only: 0,
-        right_only: 0,
-        total_files: 0,
-    };
+/// Line-ending style and final-newline state of one merge input
+#[derive(Debug, Clone, Copy)]
+struct LineFormat {
+    crlf: Option<bool>, // None when the text has no line breaks
+    mixed: bool,
+    final_newline: bool,
+}
 
-    let entries = build_compare_tree(&left_entries, &right_entries, &mut stats);
+/// Detect the dominant line ending of a text and whether it ends with one
+fn detect_line_format(text: &str) -> LineFormat {
+    let total = text.matches('\n').count();
+    let crlf = text.matches("\r\n").count();
+    let lf = total - crlf;
 
-    Ok(DirectoryCompareResult {
-        left_path: left_path.to_string(),
-        right_path: right_path.to_string(),
-        entries,
-        stats,
-    })
+    LineFormat {
+        crlf: if total == 0 { None } else { Some(crlf > lf) },
+        mixed: crlf > 0 && lf > 0,
+        final_newline: text.ends_with('\n'),
+    }
 }
 
-/// Scan a single directory and return tree structure
-fn matches_ignore_pattern(rel_path: &str, patterns: &Vec<String>) -> bool {
-    for pattern in patterns {
-        if pattern.ends_with('/') {
-            // Directory pattern
-            if rel_path.starts_with(pattern) || rel_path == &pattern[..pattern.len()-1] {
-                return true;
-            }
-        } else if pattern.contains('*') {
-            // Simple glob matching
-            let regex_pattern = pattern.replace('.', "\\.").replace('*', ".*");
-            if let Ok(regex) = regex::Regex::new(&format!("^{}$", regex_pattern)) {
-                if regex.is_match(rel_path) {
-                    return true;
-                }
-            }
-        } else {
-            // Exact match
-            if rel_path == pattern {
-                return true;
-            }
-        }
+/// Three-way pick of a file-level setting: whichever side changed it from
+/// base wins. Returns `None` when both sides changed it differently.
+fn merge_setting<T: PartialEq + Copy>(base: T, local: T, remote: T) -> Option<T> {
+    if local == remote || remote == base {
+        Some(local)
+    } else if local == base {
+        Some(remote)
+    } else {
+        None
     }
-    false
 }
 
-fn build_dir_tree(root: &Path, current: &Path, ignore_patterns: &Vec<String>) -> Result<Vec<DirEntry>, String> {
+/// Decide the line ending and final newline for merged output.
+/// Returns (crlf, final_newline, mismatch description).
+fn merge_line_formats(base: &str, local: &str, remote: &str) -> (bool, bool, Option<String>) {
+    let formats = [detect_line_format(base), detect_line_format(local), detect_line_format(remote)];
+    let names = ["base", "local", "remote"];
+    let mut notes: Vec<String> = Vec::new();
+
+    // Texts without line breaks have no style of their own; treat them as agreeing
+    let fallback = formats.iter().find_map(|f| f.crlf).unwrap_or(false);
+    let [b, l, r] = formats.map(|f| f.crlf.unwrap_or(fallback));
+    let style = |crlf: bool| if crlf { "CRLF" } else { "LF" };
+    let crlf = merge_setting(b, l, r).unwrap_or(l);
+    if !(b == l && l == r) {
+        notes.push(format!(
+            "line endings differ (base {}, local {}, remote {}); using {}",
+            style(b), style(l), style(r), style(crlf)
+        ));
+    }
 
-    // First, collect all directory entries
-    let dir_entries: Result<Vec<_>, _> = fs::read_dir(current)
-        .map_err(|e| format!("Failed to read {:?}: {}", current, e))?
-        .collect();
+    let [b, l, r] = formats.map(|f| f.final_newline);
+    let final_newline = merge_setting(b, l, r).unwrap_or(l);
+    if !(b == l && l == r) {
+        let state = |has: bool| if has { "yes" } else { "no" };
+        notes.push(format!(
+            "final newline differs (base {}, local {}, remote {}); using {}",
+            state(b), state(l), state(r), state(final_newline)
+        ));
+    }
 
-    let dir_entries = dir_entries.map_err(|e| e.to_string())?;
+    for (format, name) in formats.iter().zip(names) {
+        if format.mixed {
+            notes.push(format!("{} has mixed line endings", name));
+        }
+    }
 
-    // Process entries in parallel
-    let mut entries: Vec<DirEntry> = dir_entries
-        .into_par_iter()
-        .filter_map(|entry| {
-            let path = entry.path();
-            let name = entry.file_name().to_string_lossy().to_string();
+    let mismatch = if notes.is_empty() { None } else { Some(notes.join("; ")) };
+    (crlf, final_newline, mismatch)
+}
 
-            let rel_path = match path.strip_prefix(root) {
-                Ok(p) => p.to_string_lossy().to_string(),
-                Err(_) => return None,
-            };
+/// Build a conflict marker line, e.g. `<<<<<<< LOCAL`
+fn conflict_marker(ch: char, size: usize, label: &str) -> String {
+    let marker = ch.to_string().repeat(size);
+    if label.is_empty() {
+        marker
+    } else {
+        format!("{} {}", marker, label)
+    }
+}
 
-            // Skip if matches ignore patterns
-            if matches_ignore_pattern(&rel_path, ignore_patterns) {
-                return None;
-            }
+/// Render merge chunks as text, wrapping conflicts in markers
+fn render_merged_chunks(
+    chunks: &[MergeChunk],
+    base_lines: &[&str],
+    options: &MergeOptions,
+    eol: &str,
+    final_newline: bool,
+) -> String {
+    let size = options.marker_size;
+    let mut merged_lines: Vec<String> = Vec::new();
 
-            // Skip hidden files (but allow .git if not ignored)
-            if name.starts_with('.') && !rel_path.starts_with(".git/") {
-                return None;
+    for chunk in chunks {
+        match chunk.chunk_type {
+            ChunkType::Equal | ChunkType::LocalOnly | ChunkType::BothSame => {
+                merged_lines.extend(chunk.local_lines.iter().cloned());
             }
+            ChunkType::RemoteOnly => {
+                merged_lines.extend(chunk.remote_lines.iter().cloned());
+            }
+            ChunkType::Conflict => {
+                let (local, remote) = (&chunk.local_lines, &chunk.remote_lines);
+
+                // zdiff3 moves lines both sides agree on out of the conflict
+                let (prefix, suffix) = if options.conflict_style == ConflictStyle::Zdiff3 {
+                    let prefix = local.iter().zip(remote).take_while(|(l, r)| l == r).count();
+                    let max_suffix = local.len().min(remote.len()) - prefix;
+                    let suffix = local
+                        .iter()
+                        .rev()
+                        .zip(remote.iter().rev())
+                        .take(max_suffix)
+                        .take_while(|(l, r)| l == r)
+                        .count();
+                    (prefix, suffix)
+                } else {
+                    (0, 0)
+                };
 
-            if path.is_dir() {
-                // Recursively build tree for directories
-                match build_dir_tree(root, &path, ignore_patterns) {
-                    Ok(children) => {
-                        // Only include directories that have children or are not empty
-                        if !children.is_empty() {
-                            Some(DirEntry {
-                                name,
-                                rel_path,
-                                is_dir: true,
-                                size: 0,
-                                children,
-                            })
-                        } else {
-                            None
-                        }
-                    }
-                    Err(_) => None,
+                merged_lines.extend(local[..prefix].iter().cloned());
+                merged_lines.push(conflict_marker('<', size, &options.local_label));
+                merged_lines.extend(local[prefix..local.len() - suffix].iter().cloned());
+                if options.conflict_style != ConflictStyle::Merge {
+                    merged_lines.push(conflict_marker('|', size, &options.base_label));
+                    let base_range = chunk.base_start..chunk.base_start + chunk.base_count;
+                    merged_lines.extend(base_lines[base_range].iter().map(|s| s.to_string()));
                 }
-            } else if path.is_file() {
-                let size = fs::metadata(&path).ok().map(|m| m.len()).unwrap_or(0);
-                Some(DirEntry {
-                    name,
-                    rel_path,
-                    is_dir: false,
-                    size,
-                    children: Vec::new(),
-                })
-            } else {
-                None
+                merged_lines.push(conflict_marker('=', size, ""));
+                merged_lines.extend(remote[prefix..remote.len() - suffix].iter().cloned());
+                merged_lines.push(conflict_marker('>', size, &options.remote_label));
+                merged_lines.extend(local[local.len() - suffix..].iter().cloned());
             }
-        })
-        .collect();
-
-    // Sort: dirs first, then by name
-    entries.par_sort_by(|a, b| {
-        if a.is_dir != b.is_dir {
-            return a.is_dir.cmp(&b.is_dir).reverse(); // dirs first
         }
-        a.name.to_lowercase().cmp(&b.name.to_lowercase())
-    });
+    }
 
-    Ok(entries)
+    let mut merged = merged_lines.join(eol);
+    if final_newline && !merged_lines.is_empty() {
+        merged.push_str(eol);
+    }
+    merged
 }
 
-fn count_files(entries: &[DirEntry]) -> usize {
-    entries.iter().map(|e| {
-        if e.is_dir { count_files(&e.children) } else { 1 }
+/// diff3-style three-way merge.
+/// Diffs base→local and base→remote, then groups hunks whose base ranges
+/// overlap (or touch) into regions. A region changed on one side takes that
+/// side; a region changed on both sides is a conflict unless both produced
+/// the same lines.
+/// Lines are compared without their endings; the merged text uses the
+/// inputs' line-ending style and final-newline state.
+#[tauri::command]
+fn compute_three_way_diff(base: &str, local: &str, remote: &str, options: Option<MergeOptions>) -> MergeResult {
+    let options = options.unwrap_or_default();
+    let base_lines: Vec<&str> = base.lines().collect();
+    let local_lines: Vec<&str> = local.lines().collect();
+    let remote_lines: Vec<&str> = remote.lines().collect();
+
+    let local_hunks = diff_hunks(&base_lines, &local_lines, options.algorithm);
+    let remote_hunks = diff_hunks(&base_lines, &remote_lines, options.algorithm);
+
+    let mut chunks: Vec<MergeChunk> = Vec::new();
+    let push_equal = |chunks: &mut Vec<MergeChunk>, start: usize, end: usize| {
+        if start < end {
+            let lines: Vec<String> = base_lines[start..end].iter().map(|s| s.to_string()).collect();
+            chunks.push(MergeChunk {
+                chunk_type: ChunkType::Equal,
+                base_start: start,
+                base_count: end - start,
+                local_lines: lines.clone(),
+                remote_lines: lines,
+            });
+        }
+    };
+
+    let (mut li, mut ri) = (0usize, 0usize);
+    let mut pos = 0usize; // First base line not yet covered by a chunk
+
+    loop {
+        let start = match (local_hunks.get(li), remote_hunks.get(ri)) {
+            (None, None) => break,
+            (Some(l), None) => l.base.start,
+            (None, Some(r)) => r.base.start,
+            (Some(l), Some(r)) => l.base.start.min(r.base.start),
+        };
+
+        // Grow the region until no hunk from either side overlaps or touches it
+        let (l_first, r_first) = (li, ri);
+        let mut end = start;
+        loop {
+            let mut grew = false;
+            while let Some(h) = local_hunks.get(li).filter(|h| h.base.start <= end) {
+                end = end.max(h.base.end);
+                li += 1;
+                grew = true;
+            }
+            while let Some(h) = remote_hunks.get(ri).filter(|h| h.base.start <= end) {
+                end = end.max(h.base.end);
+                ri += 1;
+                grew = true;
+            }
+            if !grew {
+                break;
+            }
+        }
+
+        push_equal(&mut chunks, pos, start);
+
+        let local_region = side_region(&base_lines, &local_lines, &local_hunks[l_first..li], start, end);
+        let remote_region = side_region(&base_lines, &remote_lines, &remote_hunks[r_first..ri], start, end);
+        let chunk_type = match (li > l_first, ri > r_first) {
+            (true, false) => ChunkType::LocalOnly,
+            (false, true) => ChunkType::RemoteOnly,
+            _ if local_region == remote_region => ChunkType::BothSame,
+            _ => ChunkType::Conflict,
+        };
+
+        chunks.push(MergeChunk {
+            chunk_type,
+            base_start: start,
+            base_count: end - start,
+            local_lines: local_region,
+            remote_lines: remote_region,
+        });
+        pos = end;
+    }
+
+    push_equal(&mut chunks, pos, base_lines.len());
+
+    let conflict_count = chunks.iter().filter(|c| c.chunk_type == ChunkType::Conflict).count();
+    let (crlf, final_newline, line_ending_mismatch) = merge_line_formats(base, local, remote);
+    let eol = if crlf { "\r\n" } else { "\n" };
+    let merged_content = render_merged_chunks(&chunks, &base_lines, &options, eol, final_newline);
+
+    MergeResult {
+        chunks,
+        conflict_count,
+        merged_content,
+        line_ending: if crlf { "crlf" } else { "lf" }.to_string(),
+        final_newline,
+        line_ending_mismatch,
+    }
+}
+
+/// Read buffer size for streaming file comparison
+const COMPARE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Full-content xxh3 hash, streamed in chunks so large files aren't loaded whole
+fn file_hash(path: &Path) -> Option<u64> {
+    use std::io::Read;
+
+    let mut file = fs::File::open(path).ok()?;
+    let mut hasher = Xxh3::new();
+    let mut buf = vec![0u8; COMPARE_CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buf).ok()?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Some(hasher.digest())
+}
+
+/// Fill `buf` from `reader` as far as possible; returns the bytes read
+fn read_chunk(reader: &mut impl std::io::Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// Byte-for-byte comparison of two files, streamed in chunks
+fn files_equal(left: &Path, right: &Path) -> Option<bool> {
+    let mut left_file = fs::File::open(left).ok()?;
+    let mut right_file = fs::File::open(right).ok()?;
+    let mut left_buf = vec![0u8; COMPARE_CHUNK_SIZE];
+    let mut right_buf = vec![0u8; COMPARE_CHUNK_SIZE];
+    loop {
+        let left_n = read_chunk(&mut left_file, &mut left_buf).ok()?;
+        let right_n = read_chunk(&mut right_file, &mut right_buf).ok()?;
+        if left_buf[..left_n] != right_buf[..right_n] {
+            return Some(false);
+        }
+        if left_n == 0 {
+            return Some(true);
+        }
+    }
+}
+
+/// Whether two files of equal size have the same content under `method`.
+/// Unreadable files count as different.
+fn same_content(left: &Path, right: &Path, method: CompareMethod) -> bool {
+    match method {
+        CompareMethod::Size => true,
+        CompareMethod::Hash => matches!((cached_file_hash(left), cached_file_hash(right)), (Some(l), Some(r)) if l == r),
+        CompareMethod::Bytes => files_equal(left, right).unwrap_or(false),
+    }
+}
+
+/// Bump when the cache format or hash function changes; older caches are discarded
+const HASH_CACHE_VERSION: u32 = 1;
+
+/// Maximum cached hashes; least recently used entries are evicted beyond this
+const HASH_CACHE_MAX_ENTRIES: usize = 200_000;
+
+/// Files modified this recently aren't cached: an edit within the same
+/// timestamp tick wouldn't change the mtime the entry is keyed on
+const HASH_CACHE_RACY_SECS: u64 = 2;
+
+/// A hit only refreshes an entry's last use once it's this old, so
+/// comparing the same trees again doesn't rewrite the whole cache
+const HASH_CACHE_TOUCH_SECS: u64 = 24 * 60 * 60;
+
+fn unix_now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// File identity a cached hash is valid for
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct FileStamp {
+    size: u64,
+    mtime_ns: u64,
+    inode: Option<u64>,
+}
+
+impl FileStamp {
+    fn from_metadata(meta: &fs::Metadata) -> Option<Self> {
+        let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        #[cfg(unix)]
+        let inode = {
+            use std::os::unix::fs::MetadataExt;
+            Some(meta.ino())
+        };
+        #[cfg(not(unix))]
+        let inode = None;
+
+        Some(FileStamp {
+            size: meta.len(),
+            mtime_ns: mtime.as_nanos() as u64,
+            inode,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HashCacheEntry {
+    stamp: FileStamp,
+    hash: u64,
+    last_used: u64,
+}
+
+/// On-disk cache of full-content hashes keyed by absolute path. An entry is
+/// only used while the file's size, mtime and inode are unchanged.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct HashCache {
+    version: u32,
+    entries: HashMap<String, HashCacheEntry>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl HashCache {
+    /// Load from `path`, starting empty if it's missing, unreadable or from
+    /// another cache version. `None` keeps the cache in memory only.
+    fn load(path: Option<PathBuf>) -> Self {
+        let cache = path
+            .as_ref()
+            .and_then(|p| fs::read(p).ok())
+            .and_then(|bytes| serde_json::from_slice::<HashCache>(&bytes).ok())
+            .filter(|c| c.version == HASH_CACHE_VERSION)
+            .unwrap_or_default();
+
+        HashCache {
+            version: HASH_CACHE_VERSION,
+            entries: cache.entries,
+            path,
+            dirty: false,
+        }
+    }
+
+    fn get(&mut self, key: &str, stamp: &FileStamp) -> Option<u64> {
+        let entry = self.entries.get_mut(key)?;
+        if entry.stamp != *stamp {
+            return None;
+        }
+        let now = unix_now_secs();
+        if now.saturating_sub(entry.last_used) >= HASH_CACHE_TOUCH_SECS {
+            entry.last_used = now;
+            self.dirty = true;
+        }
+        Some(entry.hash)
+    }
+
+    fn insert(&mut self, key: String, stamp: FileStamp, hash: u64) {
+        let now = unix_now_secs();
+        if stamp.mtime_ns / 1_000_000_000 + HASH_CACHE_RACY_SECS > now {
+            return;
+        }
+        self.entries.insert(key, HashCacheEntry { stamp, hash, last_used: now });
+        self.dirty = true;
+    }
+
+    /// Drop least recently used entries until at most `max` remain
+    fn evict_to(&mut self, max: usize) {
+        if self.entries.len() <= max {
+            return;
+        }
+        let mut by_age: Vec<(u64, String)> = self
+            .entries
+            .iter()
+            .map(|(key, entry)| (entry.last_used, key.clone()))
+            .collect();
+        by_age.sort_unstable();
+        let excess = self.entries.len() - max;
+        for (_, key) in by_age.into_iter().take(excess) {
+            self.entries.remove(&key);
+        }
+        self.dirty = true;
+    }
+
+    /// Write the cache back to disk if it changed
+    fn save(&mut self) -> Result<(), String> {
+        let Some(path) = self.path.clone() else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+        self.evict_to(HASH_CACHE_MAX_ENTRIES);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let bytes = serde_json::to_vec(self).map_err(|e| e.to_string())?;
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, bytes).map_err(|e| e.to_string())?;
+        fs::rename(&tmp, &path).map_err(|e| e.to_string())?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<(), String> {
+        self.entries.clear();
+        self.dirty = false;
+        match &self.path {
+            Some(path) if path.exists() => fs::remove_file(path).map_err(|e| e.to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+static HASH_CACHE: OnceLock<Mutex<HashCache>> = OnceLock::new();
+
+fn hash_cache_path() -> Option<PathBuf> {
+    // Tests never touch the user's cache
+    if cfg!(test) {
+        return None;
+    }
+    dirs::cache_dir().map(|dir| dir.join("diffvibe").join("hash-cache.json"))
+}
+
+fn hash_cache() -> &'static Mutex<HashCache> {
+    HASH_CACHE.get_or_init(|| Mutex::new(HashCache::load(hash_cache_path())))
+}
+
+/// Full-content hash, reusing the cached value while the file is unchanged
+fn cached_file_hash(path: &Path) -> Option<u64> {
+    let stamp = fs::metadata(path).ok().and_then(|meta| FileStamp::from_metadata(&meta));
+    let key = std::path::absolute(path).ok().map(|p| p.to_string_lossy().to_string());
+
+    let (Some(stamp), Some(key)) = (stamp, key) else {
+        return file_hash(path);
+    };
+
+    if let Some(hash) = hash_cache().lock().ok().and_then(|mut cache| cache.get(&key, &stamp)) {
+        return Some(hash);
+    }
+
+    let hash = file_hash(path)?;
+    if let Ok(mut cache) = hash_cache().lock() {
+        cache.insert(key, stamp, hash);
+    }
+    Some(hash)
+}
+
+/// Persist hashes computed during a comparison. Failures only cost a rehash
+/// next time, so they're logged rather than returned.
+fn save_hash_cache() {
+    if let Ok(mut cache) = hash_cache().lock() {
+        if let Err(e) = cache.save() {
+            debug!("Failed to save hash cache: {}", e);
+        }
+    }
+}
+
+/// Forget all cached file hashes
+#[tauri::command]
+fn clear_hash_cache() -> Result<(), String> {
+    hash_cache().lock().map_err(|e| e.to_string())?.clear()
+}
+
+/// Error returned by a comparison stopped through `cancel_comparison`
+pub const CANCELLED_ERROR: &str = "Cancelled";
+
+/// Cooperative cancellation flag shared between a comparison and `cancel_comparison`
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Whether both are the same token rather than two with equal state
+    fn same_as(&self, other: &CancelToken) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    /// `Err(CANCELLED_ERROR)` once cancelled, for use with `?`
+    pub fn check(&self) -> Result<(), String> {
+        if self.is_cancelled() {
+            Err(CANCELLED_ERROR.to_string())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+// Running comparisons by id, so the frontend can cancel them
+static RUNNING_COMPARISONS: OnceLock<Mutex<HashMap<String, CancelToken>>> = OnceLock::new();
+
+fn running_comparisons() -> &'static Mutex<HashMap<String, CancelToken>> {
+    RUNNING_COMPARISONS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Registers a comparison's token for its lifetime; unregisters on drop
+struct ComparisonRegistration {
+    id: Option<String>,
+    token: CancelToken,
+}
+
+impl ComparisonRegistration {
+    fn new(id: Option<String>) -> Self {
+        let token = CancelToken::default();
+        if let (Some(id), Ok(mut running)) = (&id, running_comparisons().lock()) {
+            running.insert(id.clone(), token.clone());
+        }
+        ComparisonRegistration { id, token }
+    }
+}
+
+impl Drop for ComparisonRegistration {
+    fn drop(&mut self) {
+        if let (Some(id), Ok(mut running)) = (&self.id, running_comparisons().lock()) {
+            // A newer run may have reused the id; leave its token in place
+            if running.get(id).is_some_and(|token| token.same_as(&self.token)) {
+                running.remove(id);
+            }
+        }
+    }
+}
+
+/// Cancel a running comparison. Returns false if no comparison has that id.
+#[tauri::command]
+fn cancel_comparison(id: &str) -> Result<bool, String> {
+    let running = running_comparisons().lock().map_err(|e| e.to_string())?;
+    match running.get(id) {
+        Some(token) => {
+            info!("Cancelling comparison {}", id);
+            token.cancel();
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Per-directory ignore files, in increasing order of precedence
+const IGNORE_FILE_NAMES: [&str; 2] = [".gitignore", ".ignore"];
+
+/// How directory scans treat dotfiles and dot-directories
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HiddenFiles {
+    /// Show hidden entries even when the ignore rules match them, except
+    /// `.git`, which the rules still decide
+    Include,
+    /// Skip every hidden entry
+    #[default]
+    Exclude,
+    /// Treat hidden entries like any other, subject to the ignore rules
+    IgnoreRules,
+}
+
+/// What directory scans include and how they walk the tree
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScanOptions {
+    pub hidden_files: HiddenFiles,
+    /// Descend into symlinked directories and compare link targets' contents
+    /// instead of the links themselves
+    pub follow_symlinks: bool,
+    /// Record mode bits, mtime and ownership, and report entries that differ
+    /// only in those as metadata-only
+    pub compare_metadata: bool,
+    /// Levels below the root to scan; directories at the limit are listed
+    /// without children. `None` scans everything.
+    pub max_depth: Option<usize>,
+}
+
+/// Options for `compare_directories` / `compare_directories_async`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompareOptions {
+    /// Gitignore-style patterns applied from both roots
+    pub ignore_patterns: Vec<String>,
+    pub method: CompareMethod,
+    #[serde(flatten)]
+    pub scan: ScanOptions,
+}
+
+/// Gitignore-style rules in effect for one directory: the scan's own patterns
+/// (rooted at the scan root) plus every `.gitignore`/`.ignore` found on the way
+/// down. Each file is compiled once; children share their parent's matchers.
+#[derive(Clone)]
+struct IgnoreRules {
+    // Shallowest first; deeper files take precedence
+    matchers: Vec<Arc<Gitignore>>,
+    hidden: HiddenFiles,
+}
+
+impl IgnoreRules {
+    fn new(root: &Path, patterns: &[String], hidden: HiddenFiles) -> Result<Self, String> {
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in patterns {
+            builder
+                .add_line(None, pattern)
+                .map_err(|e| format!("Invalid ignore pattern {:?}: {}", pattern, e))?;
+        }
+        let matcher = builder.build().map_err(|e| e.to_string())?;
+        Ok(IgnoreRules { matchers: vec![Arc::new(matcher)], hidden })
+    }
+
+    /// Rules for the contents of `dir`, a directory under `root`, including
+    /// ignore files in every directory from `root` down to `dir`'s parent
+    fn for_dir(root: &Path, patterns: &[String], hidden: HiddenFiles, dir: &Path) -> Result<Self, String> {
+        let rel = dir
+            .strip_prefix(root)
+            .map_err(|_| format!("{} is not inside {}", dir.display(), root.display()))?;
+        let mut rules = Self::new(root, patterns, hidden)?;
+        let mut current = root.to_path_buf();
+        for component in rel.components() {
+            rules = rules.descend(&current);
+            current.push(component);
+        }
+        Ok(rules)
+    }
+
+    /// Rules for the entries of `dir`, adding its own ignore files if any
+    fn descend(&self, dir: &Path) -> Self {
+        let mut rules = self.clone();
+        for name in IGNORE_FILE_NAMES {
+            let file = dir.join(name);
+            if !file.is_file() {
+                continue;
+            }
+            let mut builder = GitignoreBuilder::new(dir);
+            // Invalid lines are reported but the valid ones still apply
+            if let Some(e) = builder.add(&file) {
+                debug!("Problem reading {}: {}", file.display(), e);
+            }
+            match builder.build() {
+                Ok(matcher) if !matcher.is_empty() => rules.matchers.push(Arc::new(matcher)),
+                Ok(_) => {}
+                Err(e) => debug!("Ignoring {}: {}", file.display(), e),
+            }
+        }
+        rules
+    }
+
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        for matcher in self.matchers.iter().rev() {
+            match matcher.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+
+    /// Whether a scan should leave out the entry `name` at `path`
+    fn skips(&self, name: &str, path: &Path, is_dir: bool) -> bool {
+        let is_hidden = name.starts_with('.');
+        match self.hidden {
+            HiddenFiles::Exclude if is_hidden => true,
+            // A repository's .git is left to the rules even when showing hidden entries
+            HiddenFiles::Include if is_hidden && name != ".git" => false,
+            _ => self.is_ignored(path, is_dir),
+        }
+    }
+}
+
+/// Per-directory state of a recursive scan. When following links, the
+/// canonical paths of the directories above are kept to detect loops.
+#[derive(Debug, Clone, Default)]
+struct WalkState {
+    follow_symlinks: bool,
+    collect_metadata: bool,
+    // Levels left to list, if limited
+    depth_left: Option<usize>,
+    // Outermost first
+    ancestors: Vec<PathBuf>,
+}
+
+impl WalkState {
+    /// State for scanning `root` itself
+    fn new(options: &ScanOptions, root: &Path) -> Self {
+        let ancestors = match fs::canonicalize(root) {
+            Ok(canonical) if options.follow_symlinks => vec![canonical],
+            _ => Vec::new(),
+        };
+        WalkState {
+            follow_symlinks: options.follow_symlinks,
+            collect_metadata: options.compare_metadata,
+            depth_left: options.max_depth,
+            ancestors,
+        }
+    }
+
+    /// State for scanning inside `dir`, or `None` if that would revisit a
+    /// directory already being scanned
+    fn enter(&self, dir: &Path) -> Option<Self> {
+        let mut inner = self.clone();
+        inner.depth_left = self.depth_left.map(|d| d.saturating_sub(1));
+        if !self.follow_symlinks {
+            return Some(inner);
+        }
+        let canonical = fs::canonicalize(dir).ok()?;
+        if self.ancestors.contains(&canonical) {
+            debug!("Not following {}: symlink loop", dir.display());
+            return None;
+        }
+        inner.ancestors.push(canonical);
+        Some(inner)
+    }
+}
+
+/// Describe `path` as a childless entry, following it only if it's a symlink
+/// and the walk follows links
+fn describe_entry(path: &Path, name: String, rel_path: String, file_type: fs::FileType, walk: &WalkState) -> DirEntry {
+    let is_link = file_type.is_symlink();
+    let link_target = if is_link {
+        fs::read_link(path).ok().map(|t| t.to_string_lossy().to_string())
+    } else {
+        None
+    };
+
+    let meta = if is_link && walk.follow_symlinks { fs::metadata(path) } else { fs::symlink_metadata(path) };
+    let (kind, size) = match &meta {
+        Ok(m) if m.is_dir() => (EntryKind::Dir, 0),
+        Ok(m) if m.is_file() => (EntryKind::File, m.len()),
+        _ if is_link => (EntryKind::Symlink, 0),
+        _ => (EntryKind::Other, 0),
+    };
+    let metadata = match (&meta, kind) {
+        (Ok(m), EntryKind::File | EntryKind::Dir) if walk.collect_metadata => Some(FileMetadata::from_metadata(m)),
+        _ => None,
+    };
+
+    DirEntry {
+        name,
+        rel_path,
+        is_dir: kind == EntryKind::Dir,
+        size,
+        kind,
+        link_target,
+        metadata,
+        children: Vec::new(),
+    }
+}
+
+fn build_dir_tree(
+    root: &Path,
+    current: &Path,
+    rules: &IgnoreRules,
+    walk: &WalkState,
+    cancel: &CancelToken,
+) -> Result<Vec<DirEntry>, String> {
+    // Past the depth limit, directories are listed without children
+    if walk.depth_left == Some(0) {
+        return Ok(Vec::new());
+    }
+    cancel.check()?;
+    let rules = rules.descend(current);
+
+    // First, collect all directory entries
+    let dir_entries: Result<Vec<_>, _> = fs::read_dir(current)
+        .map_err(|e| format!("Failed to read {:?}: {}", current, e))?
+        .collect();
+
+    let dir_entries = dir_entries.map_err(|e| e.to_string())?;
+
+    // Process entries in parallel
+    let mut entries: Vec<DirEntry> = dir_entries
+        .into_par_iter()
+        .filter_map(|entry| {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+
+            let rel_path = match path.strip_prefix(root) {
+                Ok(p) => p.to_string_lossy().to_string(),
+                Err(_) => return None,
+            };
+
+            let mut dir_entry = describe_entry(&path, name, rel_path, entry.file_type().ok()?, walk);
+
+            // Skip hidden and ignored entries
+            if rules.skips(&dir_entry.name, &path, dir_entry.is_dir) {
+                return None;
+            }
+
+            if dir_entry.is_dir {
+                match walk.enter(&path) {
+                    // Unreadable directories are listed without children
+                    Some(inner) => {
+                        dir_entry.children = build_dir_tree(root, &path, &rules, &inner, cancel).unwrap_or_default();
+                    }
+                    None => dir_entry.mark_loop(),
+                }
+            }
+
+            Some(dir_entry)
+        })
+        .collect();
+
+    // Subdirectory errors are skipped above, so surface cancellation here
+    cancel.check()?;
+
+    // Sort: dirs first, then by name
+    entries.par_sort_by(|a, b| {
+        if a.is_dir != b.is_dir {
+            return a.is_dir.cmp(&b.is_dir).reverse(); // dirs first
+        }
+        a.name.to_lowercase().cmp(&b.name.to_lowercase())
+    });
+
+    Ok(entries)
+}
+
+fn count_files(entries: &[DirEntry]) -> usize {
+    entries.iter().map(|e| {
+        if e.is_dir { count_files(&e.children) } else { 1 }
     }).sum()
 }
 
@@ -1115,18 +2725,27 @@ fn merge_directory_trees(left_entries: &[DirEntry], right_entries: &[DirEntry])
         let status = match (left, right) {
             (Some(l), Some(r)) => {
                 // Both exist
-                if l.is_dir != r.is_dir {
-                    EntryStatus::Modified
-                } else if l.is_dir {
+                let same = match (l.kind, r.kind) {
                     // Both are directories - status will be refined based on children
-                    EntryStatus::Match
-                } else {
+                    (EntryKind::Dir, EntryKind::Dir) => true,
                     // Both are files - compare size
-                    if l.size == r.size {
-                        EntryStatus::Match
-                    } else {
-                        EntryStatus::Modified
-                    }
+                    (EntryKind::File, EntryKind::File) => l.size == r.size,
+                    // Unfollowed links are equal if they point to the same place
+                    (EntryKind::Symlink, EntryKind::Symlink) => l.link_target == r.link_target,
+                    // Special files have no content to compare
+                    (EntryKind::Other, EntryKind::Other) => true,
+                    _ => false,
+                };
+                let metadata_differs = match (&l.metadata, &r.metadata) {
+                    (Some(lm), Some(rm)) => lm.differs(rm, l.is_dir),
+                    _ => false,
+                };
+                if !same {
+                    EntryStatus::Modified
+                } else if metadata_differs {
+                    EntryStatus::MetadataOnly
+                } else {
+                    EntryStatus::Match
                 }
             },
             (Some(_), None) => EntryStatus::LeftOnly,
@@ -1144,15 +2763,7 @@ fn merge_directory_trees(left_entries: &[DirEntry], right_entries: &[DirEntry])
         };
 
         // Refine directory status based on children
-        let final_status = if is_dir && matches!(status, EntryStatus::Match) {
-            if children.iter().any(|c| !matches!(c.status, EntryStatus::Match)) {
-                EntryStatus::Modified
-            } else {
-                EntryStatus::Match
-            }
-        } else {
-            status
-        };
+        let final_status = if is_dir { refine_dir_status(status, &children) } else { status };
 
         result.push(AlignedEntry {
             name: name.to_string(),
@@ -1160,20 +2771,112 @@ fn merge_directory_trees(left_entries: &[DirEntry], right_entries: &[DirEntry])
             is_dir,
             left_size,
             right_size,
+            left_kind: left.map(|e| e.kind),
+            right_kind: right.map(|e| e.kind),
+            left_link_target: left.and_then(|e| e.link_target.clone()),
+            right_link_target: right.and_then(|e| e.link_target.clone()),
+            left_metadata: left.and_then(|e| e.metadata.clone()),
+            right_metadata: right.and_then(|e| e.metadata.clone()),
             status: final_status,
             children,
         });
     }
 
-    // Sort: directories first, then by name
-    result.par_sort_by(|a, b| {
-        if a.is_dir != b.is_dir {
-            return b.is_dir.cmp(&a.is_dir);
+    result.par_sort_by(compare_aligned);
+    result
+}
+
+/// Order of aligned entries: directories first, then by name
+fn compare_aligned(a: &AlignedEntry, b: &AlignedEntry) -> std::cmp::Ordering {
+    if a.is_dir != b.is_dir {
+        return b.is_dir.cmp(&a.is_dir);
+    }
+    a.name.to_lowercase().cmp(&b.name.to_lowercase())
+}
+
+/// A directory present on both sides is modified, or metadata-only, if
+/// anything below it is
+fn refine_dir_status(status: EntryStatus, children: &[AlignedEntry]) -> EntryStatus {
+    if !matches!(status, EntryStatus::Match | EntryStatus::MetadataOnly) {
+        status
+    } else if children.iter().any(|c| c.status.is_content_change()) {
+        EntryStatus::Modified
+    } else if children.iter().any(|c| matches!(c.status, EntryStatus::MetadataOnly)) {
+        EntryStatus::MetadataOnly
+    } else {
+        status
+    }
+}
+
+/// How many files to hash between `directory-scan-progress` events
+const HASH_PROGRESS_INTERVAL: usize = 256;
+
+/// Collect rel_paths of files that `merge_directory_trees` matched by size,
+/// including those whose metadata alone differs
+fn collect_size_matches<'a>(entries: &'a [AlignedEntry], out: &mut Vec<&'a str>) {
+    for entry in entries {
+        if entry.is_dir {
+            collect_size_matches(&entry.children, out);
+        } else if !entry.status.is_content_change() && entry.left_kind == Some(EntryKind::File) {
+            out.push(&entry.rel_path);
         }
-        a.name.to_lowercase().cmp(&b.name.to_lowercase())
-    });
+    }
+}
 
-    result
+/// Mark files in `differing` as modified and propagate to the directories
+/// present on both sides above them
+fn apply_content_status(entries: &mut [AlignedEntry], differing: &std::collections::HashSet<String>) {
+    for entry in entries.iter_mut() {
+        if entry.is_dir {
+            apply_content_status(&mut entry.children, differing);
+            entry.status = refine_dir_status(entry.status.clone(), &entry.children);
+        } else if differing.contains(&entry.rel_path) {
+            entry.status = EntryStatus::Modified;
+        }
+    }
+}
+
+/// Check the content of size-matched files in parallel, downgrading any that
+/// differ to modified. `on_progress(done, total)` is called periodically.
+fn refine_content_status(
+    entries: &mut [AlignedEntry],
+    left_root: &Path,
+    right_root: &Path,
+    method: CompareMethod,
+    cancel: &CancelToken,
+    on_progress: impl Fn(usize, usize) + Sync,
+) -> Result<(), String> {
+    use std::sync::atomic::AtomicUsize;
+
+    if method == CompareMethod::Size {
+        return Ok(());
+    }
+
+    let mut candidates = Vec::new();
+    collect_size_matches(entries, &mut candidates);
+    let total = candidates.len();
+    let done = AtomicUsize::new(0);
+
+    let differing: std::collections::HashSet<String> = candidates
+        .into_par_iter()
+        .filter(|rel_path| {
+            if cancel.is_cancelled() {
+                return false;
+            }
+            let same = same_content(&left_root.join(rel_path), &right_root.join(rel_path), method);
+            let n = done.fetch_add(1, Ordering::Relaxed) + 1;
+            if n.is_multiple_of(HASH_PROGRESS_INTERVAL) || n == total {
+                on_progress(n, total);
+            }
+            !same
+        })
+        .map(|rel_path| rel_path.to_string())
+        .collect();
+    cancel.check()?;
+
+    debug!("Content check: {} of {} size-matched files differ", differing.len(), total);
+    apply_content_status(entries, &differing);
+    Ok(())
 }
 
 /// Count statistics from aligned entries
@@ -1183,6 +2886,7 @@ fn count_aligned_stats(entries: &[AlignedEntry]) -> CompareStats {
         modified: 0,
         left_only: 0,
         right_only: 0,
+        metadata_only: 0,
         total_files: 0,
     };
 
@@ -1197,6 +2901,7 @@ fn count_aligned_stats(entries: &[AlignedEntry]) -> CompareStats {
                     EntryStatus::Modified => stats.modified += 1,
                     EntryStatus::LeftOnly => stats.left_only += 1,
                     EntryStatus::RightOnly => stats.right_only += 1,
+                    EntryStatus::MetadataOnly => stats.metadata_only += 1,
                 }
             }
         }
@@ -1208,7 +2913,7 @@ fn count_aligned_stats(entries: &[AlignedEntry]) -> CompareStats {
 
 /// Get just diff stats for two files (lightweight, no full diff)
 #[tauri::command]
-fn get_diff_stats(left_path: &str, right_path: &str) -> Result<DiffStats, String> {
+fn get_diff_stats(left_path: &str, right_path: &str, options: Option<DiffOptions>) -> Result<DiffStats, String> {
     let left = read_file(left_path)?;
     let right = read_file(right_path)?;
 
@@ -1229,19 +2934,27 @@ fn get_diff_stats(left_path: &str, right_path: &str) -> Result<DiffStats, String
         });
     }
 
-    let diff = compute_diff(&left.content, &right.content);
+    let diff = compute_diff(&left.content, &right.content, options);
     Ok(diff.stats)
 }
 
 #[tauri::command]
-async fn scan_directory_lazy(path: &str, ignore_patterns: Vec<String>, max_depth: usize) -> Result<ScanResult, String> {
+async fn scan_directory_lazy(
+    path: &str,
+    ignore_patterns: Vec<String>,
+    max_depth: usize,
+    scan_options: Option<ScanOptions>,
+) -> Result<ScanResult, String> {
     let root = Path::new(path);
     if !root.is_dir() {
         return Err(format!("{} is not a directory", path));
     }
 
     // Only scan top-level entries, don't recurse deeply
-    let entries = build_dir_tree_lazy(root, root, &ignore_patterns, 0, max_depth)?;
+    let scan_options = ScanOptions { max_depth: Some(max_depth), ..scan_options.unwrap_or_default() };
+    let rules = IgnoreRules::new(root, &ignore_patterns, scan_options.hidden_files)?;
+    let walk = WalkState::new(&scan_options, root);
+    let entries = build_dir_tree(root, root, &rules, &walk, &CancelToken::default())?;
     let file_count = count_files(&entries);
 
     Ok(ScanResult {
@@ -1252,7 +2965,12 @@ async fn scan_directory_lazy(path: &str, ignore_patterns: Vec<String>, max_depth
 }
 
 #[tauri::command]
-async fn expand_directory(path: &str, rel_path: &str, ignore_patterns: Vec<String>) -> Result<Vec<DirEntry>, String> {
+async fn expand_directory(
+    path: &str,
+    rel_path: &str,
+    ignore_patterns: Vec<String>,
+    scan_options: Option<ScanOptions>,
+) -> Result<Vec<DirEntry>, String> {
     let root = Path::new(path);
     let target = root.join(rel_path);
     
@@ -1261,393 +2979,2488 @@ async fn expand_directory(path: &str, rel_path: &str, ignore_patterns: Vec<Strin
     }
 
     // Scan just this directory's children (one level)
-    let entries = build_dir_tree_lazy(root, &target, &ignore_patterns, 0, 1)?;
+    let scan_options = ScanOptions { max_depth: Some(1), ..scan_options.unwrap_or_default() };
+    let rules = IgnoreRules::for_dir(root, &ignore_patterns, scan_options.hidden_files, &target)?;
+    let walk = WalkState::new(&scan_options, &target);
+    let entries = build_dir_tree(root, &target, &rules, &walk, &CancelToken::default())?;
     
     Ok(entries)
 }
 
-fn build_dir_tree_lazy(
-    root: &Path,
-    current: &Path,
-    ignore_patterns: &Vec<String>,
-    current_depth: usize,
-    max_depth: usize,
-) -> Result<Vec<DirEntry>, String> {
-    // Stop recursion at max depth
-    if current_depth >= max_depth {
-        return Ok(Vec::new());
+#[tauri::command]
+async fn scan_directory(
+    path: &str,
+    ignore_patterns: Vec<String>,
+    scan_options: Option<ScanOptions>,
+) -> Result<ScanResult, String> {
+    let root = Path::new(path);
+    if !root.is_dir() {
+        return Err(format!("{} is not a directory", path));
     }
 
-    // First, collect all directory entries
-    let dir_entries: Result<Vec<_>, _> = fs::read_dir(current)
-        .map_err(|e| format!("Failed to read {:?}: {}", current, e))?
-        .collect();
+    let path_owned = path.to_string();
+    let ignore_patterns = ignore_patterns.clone();
 
-    let dir_entries = dir_entries.map_err(|e| e.to_string())?;
+    // Run in blocking thread pool to avoid blocking async runtime
+    let result = tokio::task::spawn_blocking(move || {
+        let root = Path::new(&path_owned);
+        let scan_options = scan_options.unwrap_or_default();
+        let rules = IgnoreRules::new(root, &ignore_patterns, scan_options.hidden_files)?;
+        let walk = WalkState::new(&scan_options, root);
+        let entries = build_dir_tree(root, root, &rules, &walk, &CancelToken::default())?;
+        let file_count = count_files(&entries);
 
-    // Process entries in parallel
-    let mut entries: Vec<DirEntry> = dir_entries
-        .into_par_iter()
-        .filter_map(|entry| {
-            let path = entry.path();
-            let name = entry.file_name().to_string_lossy().to_string();
+        Ok::<ScanResult, String>(ScanResult {
+            root_path: path_owned,
+            entries,
+            file_count,
+        })
+    })
+    .await
+    .map_err(|e| format!("Scan task failed: {}", e))??;
 
-            let rel_path = match path.strip_prefix(root) {
-                Ok(p) => p.to_string_lossy().to_string(),
-                Err(_) => return None,
-            };
+    Ok(result)
+}
 
-            // Skip if matches ignore patterns
-            if matches_ignore_pattern(&rel_path, ignore_patterns) {
-                return None;
-            }
+/// Below this many subtrees, a compare lists directories one level deeper so
+/// that trees with few top-level directories still stream in parts
+const COMPARE_MIN_SUBTREES: usize = 16;
+
+/// Deepest level a compare lists before scanning the subtrees below it whole
+const COMPARE_MAX_SPLIT_DEPTH: usize = 4;
+
+/// One side of a directory in a comparison, with the rules and walk state
+/// `build_dir_tree` needs to scan it
+#[derive(Clone)]
+struct CompareSide<'a> {
+    root: &'a Path,
+    dir: PathBuf,
+    rules: IgnoreRules,
+    walk: WalkState,
+}
 
-            // Skip hidden files (but allow .git if not ignored)
-            if name.starts_with('.') && !rel_path.starts_with(".git/") {
-                return None;
+impl<'a> CompareSide<'a> {
+    fn new(root: &'a Path, options: &CompareOptions) -> Result<Self, String> {
+        Ok(CompareSide {
+            root,
+            dir: root.to_path_buf(),
+            rules: IgnoreRules::new(root, &options.ignore_patterns, options.scan.hidden_files)?,
+            walk: WalkState::new(&options.scan, root),
+        })
+    }
+
+    /// List the directory without recursing
+    fn list(&self, cancel: &CancelToken) -> Result<Listing<'a>, String> {
+        let listing = WalkState {
+            depth_left: Some(self.walk.depth_left.map_or(1, |d| d.min(1))),
+            ..self.walk.clone()
+        };
+        let entries = match build_dir_tree(self.root, &self.dir, &self.rules, &listing, cancel) {
+            Ok(entries) => entries,
+            // Unreadable directories below the root are listed without children
+            Err(e) if self.dir != self.root => {
+                cancel.check()?;
+                debug!("compare: {}", e);
+                Vec::new()
             }
+            Err(e) => return Err(e),
+        };
+        let inner = self.rules.descend(&self.dir);
+        Ok(entries
+            .into_iter()
+            .map(|entry| {
+                let dir = self.dir.join(&entry.name);
+                let walk = if entry.is_dir { self.walk.enter(&dir) } else { None };
+                let side = walk.map(|walk| CompareSide { root: self.root, dir, rules: inner.clone(), walk });
+                (entry, side)
+            })
+            .collect())
+    }
 
-            if path.is_dir() {
-                // For directories, only recurse if within depth limit
-                let children = if current_depth + 1 < max_depth {
-                    match build_dir_tree_lazy(root, &path, ignore_patterns, current_depth + 1, max_depth) {
-                        Ok(ch) => ch,
-                        Err(_) => Vec::new(),
-                    }
+    /// Scan the whole subtree below an entry from `list`
+    fn fill((mut entry, side): (DirEntry, Option<Self>), cancel: &CancelToken) -> DirEntry {
+        if let Some(side) = side {
+            // Unreadable directories are listed without children
+            entry.children = build_dir_tree(side.root, &side.dir, &side.rules, &side.walk, cancel).unwrap_or_default();
+        }
+        entry
+    }
+}
+
+/// Entries of one side of a directory, each with the side for scanning
+/// inside it if it's a directory the walk can enter
+type Listing<'a> = Vec<(DirEntry, Option<CompareSide<'a>>)>;
+
+/// A directory listed during a compare, on whichever sides have it
+struct CompareDir<'a> {
+    rel_path: String,
+    left: Option<CompareSide<'a>>,
+    right: Option<CompareSide<'a>>,
+}
+
+impl<'a> CompareDir<'a> {
+    /// List both sides; a side without the directory lists as empty
+    fn list(&self, cancel: &CancelToken) -> Result<(Listing<'a>, Listing<'a>), String> {
+        let list = |side: &Option<CompareSide<'a>>| side.as_ref().map_or(Ok(Vec::new()), |s| s.list(cancel));
+        Ok((list(&self.left)?, list(&self.right)?))
+    }
+}
+
+/// Entries of one listed directory that are scanned whole and sent as a batch
+struct CompareUnit<'a> {
+    parent: String,
+    left: Listing<'a>,
+    right: Listing<'a>,
+}
+
+/// Put the batches of a compare back together below `parent`. Directories
+/// that were listed rather than scanned whole get their children and status
+/// from the batches inside them.
+fn assemble_batches(parent: &str, batches: &mut HashMap<String, Vec<AlignedEntry>>) -> Vec<AlignedEntry> {
+    let mut entries = batches.remove(parent).unwrap_or_default();
+    for entry in &mut entries {
+        if entry.is_dir && batches.contains_key(&entry.rel_path) {
+            entry.children = assemble_batches(&entry.rel_path, batches);
+            entry.status = refine_dir_status(entry.status.clone(), &entry.children);
+        }
+    }
+    entries.sort_by(compare_aligned);
+    entries
+}
+
+/// The directory compare engine behind both compare commands. Directories
+/// are listed level by level from the roots until there are enough subtrees;
+/// the subtrees, and the files of each listed directory, are then scanned,
+/// aligned and content-checked in parallel. `on_batch(parent, entries)` gets
+/// the listed directories first, then each part as it finishes.
+fn compare_directory_trees(
+    left_path: &str,
+    right_path: &str,
+    options: &CompareOptions,
+    cancel: &CancelToken,
+    on_progress: impl Fn(ScanProgress) + Sync,
+    on_batch: impl Fn(&str, &[AlignedEntry]) + Sync,
+) -> Result<AlignedScanResult, String> {
+    use std::sync::atomic::AtomicUsize;
+
+    let left_root = Path::new(left_path);
+    let right_root = Path::new(right_path);
+
+    if !left_root.is_dir() {
+        return Err(format!("{} is not a directory", left_path));
+    }
+    if !right_root.is_dir() {
+        return Err(format!("{} is not a directory", right_path));
+    }
+
+    let progress = |phase: &str, files: usize, message: String| {
+        on_progress(ScanProgress { phase: phase.to_string(), files, message });
+    };
+
+    // Phase 1: List directories breadth-first until there are enough subtrees
+    progress("scanning", 0, format!("Scanning {} and {}...", left_path, right_path));
+    let mut batches: HashMap<String, Vec<AlignedEntry>> = HashMap::new();
+    let mut units = Vec::new();
+    let mut level = vec![CompareDir {
+        rel_path: String::new(),
+        left: Some(CompareSide::new(left_root, options)?),
+        right: Some(CompareSide::new(right_root, options)?),
+    }];
+    for depth in 1.. {
+        let mut subdirs = Vec::new();
+        for dir in level {
+            let (left, right) = dir.list(cancel)?;
+            let entries = |listed: &[(DirEntry, _)]| listed.iter().map(|(e, _)| e.clone()).collect::<Vec<_>>();
+            let aligned = merge_directory_trees(&entries(&left), &entries(&right));
+
+            let by_name = |listed: Vec<_>| listed.into_iter().map(|(e, s): (DirEntry, _)| (e.name.clone(), (e, s))).collect::<HashMap<_, _>>();
+            let (mut left, mut right) = (by_name(left), by_name(right));
+            let mut files = CompareUnit { parent: dir.rel_path.clone(), left: Vec::new(), right: Vec::new() };
+            for entry in aligned {
+                let l = left.remove(&entry.name);
+                let r = right.remove(&entry.name);
+                // Only directories the walk can enter on every side they're on are listed further
+                let enterable = |side: &Option<(DirEntry, Option<CompareSide>)>| side.as_ref().is_none_or(|(_, s)| s.is_some());
+                if entry.is_dir && enterable(&l) && enterable(&r) {
+                    subdirs.push((dir.rel_path.clone(), entry, l, r));
+                } else if entry.is_dir {
+                    units.push(CompareUnit { parent: dir.rel_path.clone(), left: l.into_iter().collect(), right: r.into_iter().collect() });
                 } else {
-                    // Return empty children but mark that we have a directory
-                    Vec::new()
-                };
+                    files.left.extend(l);
+                    files.right.extend(r);
+                }
+            }
+            if !files.left.is_empty() || !files.right.is_empty() {
+                units.push(files);
+            }
+        }
 
-                Some(DirEntry {
-                    name,
-                    rel_path,
-                    is_dir: true,
-                    size: 0,
-                    children,
-                })
-            } else if path.is_file() {
-                let size = fs::metadata(&path).ok().map(|m| m.len()).unwrap_or(0);
-                Some(DirEntry {
-                    name,
-                    rel_path,
-                    is_dir: false,
-                    size,
-                    children: Vec::new(),
-                })
+        if subdirs.len() >= COMPARE_MIN_SUBTREES || depth >= COMPARE_MAX_SPLIT_DEPTH {
+            units.extend(subdirs.into_iter().map(|(parent, _, l, r)| CompareUnit {
+                parent,
+                left: l.into_iter().collect(),
+                right: r.into_iter().collect(),
+            }));
+            break;
+        }
+        if subdirs.is_empty() {
+            break;
+        }
+
+        // List the subdirectories next; send them first so later batches have a place to go
+        level = Vec::new();
+        let mut listed: Vec<(String, Vec<AlignedEntry>)> = Vec::new();
+        for (parent, entry, l, r) in subdirs {
+            level.push(CompareDir {
+                rel_path: entry.rel_path.clone(),
+                left: l.and_then(|(_, s)| s),
+                right: r.and_then(|(_, s)| s),
+            });
+            match listed.last_mut() {
+                Some((last, group)) if *last == parent => group.push(entry),
+                _ => listed.push((parent, vec![entry])),
+            }
+        }
+        for (parent, group) in listed {
+            on_batch(&parent, &group);
+            batches.insert(parent, group);
+        }
+    }
+
+    // Phase 2: Scan, align and content-check each part in parallel
+    let files_scanned = AtomicUsize::new(0);
+    let compare_units = || -> Result<Vec<(String, Vec<AlignedEntry>)>, String> {
+        units
+            .into_par_iter()
+            .map(|CompareUnit { parent, left, right }| {
+                let label = if parent.is_empty() { "top level".to_string() } else { parent.clone() };
+                let fill = |side: Vec<_>| side.into_iter().map(|e| CompareSide::fill(e, cancel)).collect::<Vec<_>>();
+                let (left, right) = rayon::join(|| fill(left), || fill(right));
+                cancel.check()?;
+                let count = count_files(&left) + count_files(&right);
+                let scanned = files_scanned.fetch_add(count, Ordering::Relaxed) + count;
+                progress("scanning", scanned, format!("Comparing {}...", label));
+
+                let mut batch = merge_directory_trees(&left, &right);
+                refine_content_status(&mut batch, left_root, right_root, options.method, cancel, |done, total| {
+                    let scanned = files_scanned.load(Ordering::Relaxed);
+                    progress("hashing", scanned, format!("Comparing contents of {} ({}/{})...", label, done, total));
+                })?;
+
+                on_batch(&parent, &batch);
+                Ok((parent, batch))
+            })
+            .collect()
+    };
+    let result = compare_units();
+    // Hashes computed before a cancel are still worth keeping
+    save_hash_cache();
+    for (parent, batch) in result? {
+        batches.entry(parent).or_default().extend(batch);
+    }
+    let entries = assemble_batches("", &mut batches);
+
+    let stats = count_aligned_stats(&entries);
+    progress("complete", stats.total_files, format!("Complete: {} files compared", stats.total_files));
+
+    Ok(AlignedScanResult {
+        root_left: left_path.to_string(),
+        root_right: right_path.to_string(),
+        entries,
+        stats,
+    })
+}
+
+/// Compare two directories and return the aligned tree
+#[tauri::command]
+fn compare_directories(
+    left_path: &str,
+    right_path: &str,
+    options: Option<CompareOptions>,
+) -> Result<AlignedScanResult, String> {
+    let options = options.unwrap_or_default();
+    compare_directory_trees(left_path, right_path, &options, &CancelToken::default(), |_| {}, |_, _| {})
+}
+
+/// Messages streamed to the frontend while `compare_directories_async` runs
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum CompareEvent {
+    /// Entries of the directory at `parent` ("" for the roots). Listed
+    /// directories come first without children; the rest have full subtrees.
+    Batch { parent: String, entries: Vec<AlignedEntry> },
+    /// Sent once after the last batch
+    Complete { stats: CompareStats },
+}
+
+/// Compare two directories and return aligned tree with progress events.
+/// With a `channel`, finished subtrees are streamed as they complete.
+#[tauri::command]
+async fn compare_directories_async(
+    window: tauri::Window,
+    left_path: String,
+    right_path: String,
+    options: Option<CompareOptions>,
+    comparison_id: Option<String>,
+    channel: Option<tauri::ipc::Channel<CompareEvent>>,
+) -> Result<AlignedScanResult, String> {
+    // Cancellable via cancel_comparison(comparison_id) until this returns
+    let registration = ComparisonRegistration::new(comparison_id);
+    let cancel = registration.token.clone();
+    let options = options.unwrap_or_default();
+
+    // Run in blocking thread pool
+    let result = tokio::task::spawn_blocking(move || {
+        let result = compare_directory_trees(
+            &left_path,
+            &right_path,
+            &options,
+            &cancel,
+            |progress| {
+                let _ = window.emit("directory-scan-progress", progress);
+            },
+            |parent, batch| {
+                if let Some(channel) = &channel {
+                    let _ = channel.send(CompareEvent::Batch { parent: parent.to_string(), entries: batch.to_vec() });
+                }
+            },
+        )?;
+        if let Some(channel) = &channel {
+            let _ = channel.send(CompareEvent::Complete { stats: result.stats.clone() });
+        }
+        Ok::<AlignedScanResult, String>(result)
+    })
+    .await
+    .map_err(|e| format!("Compare task failed: {}", e))??;
+    drop(registration);
+
+    Ok(result)
+}
+
+/// The three roots of a directory merge, with the settings every entry shares
+struct MergeRoots<'a> {
+    local: &'a Path,
+    base: &'a Path,
+    remote: &'a Path,
+    method: CompareMethod,
+    options: &'a MergeOptions,
+}
+
+impl MergeRoots<'_> {
+    /// Whether two versions of an entry are the same. Directories always are;
+    /// their children are compared separately.
+    fn same(&self, (a_root, a): (&Path, &DirEntry), (b_root, b): (&Path, &DirEntry)) -> bool {
+        match (a.kind, b.kind) {
+            (EntryKind::File, EntryKind::File) => {
+                a.size == b.size && same_content(&a_root.join(&a.rel_path), &b_root.join(&b.rel_path), self.method)
+            }
+            (EntryKind::Symlink, EntryKind::Symlink) => a.link_target == b.link_target,
+            (EntryKind::Dir, EntryKind::Dir) | (EntryKind::Other, EntryKind::Other) => true,
+            _ => false,
+        }
+    }
+
+    /// Merge the three versions of a text file and count the conflicts left.
+    /// `None` if any version is binary or unreadable.
+    fn text_conflicts(&self, rel_path: &str) -> Option<usize> {
+        let read = |root: &Path| {
+            read_file(&root.join(rel_path).to_string_lossy())
+                .ok()
+                .filter(|f| f.exists && !f.is_binary)
+        };
+        let (local, base, remote) = (read(self.local)?, read(self.base)?, read(self.remote)?);
+        let result = compute_three_way_diff(&base.content, &local.content, &remote.content, Some(self.options.clone()));
+        Some(result.conflict_count)
+    }
+}
+
+/// Align the local, base and remote versions of one entry, recursing when
+/// every version present is a directory
+fn merge_three_entry(
+    roots: &MergeRoots,
+    name: &str,
+    local: Option<&DirEntry>,
+    base: Option<&DirEntry>,
+    remote: Option<&DirEntry>,
+) -> MergeEntry {
+    let present: Vec<&DirEntry> = [local, base, remote].into_iter().flatten().collect();
+    let is_dir = present.iter().all(|e| e.is_dir);
+    let rel_path = present[0].rel_path.clone();
+
+    let children = if is_dir {
+        merge_three_trees(
+            roots,
+            local.map_or(&[], |e| e.children.as_slice()),
+            base.map_or(&[], |e| e.children.as_slice()),
+            remote.map_or(&[], |e| e.children.as_slice()),
+        )
+    } else {
+        Vec::new()
+    };
+
+    let mut conflict_count = None;
+    let mut has_conflict = false;
+    let state = match (local, base, remote) {
+        (Some(_), Some(_), Some(_)) if is_dir => {
+            let (local_changed, remote_changed) = children
+                .iter()
+                .fold((false, false), |(l, r), c| {
+                    let (cl, cr) = c.state.sides();
+                    (l || cl, r || cr)
+                });
+            MergeState::from_sides(local_changed, remote_changed)
+        }
+        (Some(l), Some(b), Some(r)) => {
+            let local_changed = !roots.same((roots.local, l), (roots.base, b));
+            let remote_changed = !roots.same((roots.remote, r), (roots.base, b));
+            if local_changed && remote_changed {
+                let all_files = [l, b, r].iter().all(|e| e.kind == EntryKind::File);
+                conflict_count = if all_files { roots.text_conflicts(&rel_path) } else { None };
+                has_conflict = match conflict_count {
+                    Some(n) => n > 0,
+                    None => !roots.same((roots.local, l), (roots.remote, r)),
+                };
+            }
+            MergeState::from_sides(local_changed, remote_changed)
+        }
+        (Some(l), None, Some(r)) if is_dir || roots.same((roots.local, l), (roots.remote, r)) => MergeState::AddedBoth,
+        (Some(_), None, Some(_)) => MergeState::AddAddConflict,
+        // A directory deleted on one side conflicts with any other change inside it
+        (Some(_), Some(_), None) if is_dir => {
+            if children.iter().all(|c| matches!(c.state, MergeState::DeletedRemote | MergeState::DeletedBoth)) {
+                MergeState::DeletedRemote
+            } else {
+                MergeState::ModifyDeleteConflict
+            }
+        }
+        (None, Some(_), Some(_)) if is_dir => {
+            if children.iter().all(|c| matches!(c.state, MergeState::DeletedLocal | MergeState::DeletedBoth)) {
+                MergeState::DeletedLocal
             } else {
-                None
+                MergeState::ModifyDeleteConflict
             }
+        }
+        (Some(l), Some(b), None) if roots.same((roots.local, l), (roots.base, b)) => MergeState::DeletedRemote,
+        (None, Some(b), Some(r)) if roots.same((roots.remote, r), (roots.base, b)) => MergeState::DeletedLocal,
+        (Some(_), Some(_), None) | (None, Some(_), Some(_)) => MergeState::ModifyDeleteConflict,
+        (Some(_), None, None) => MergeState::AddedLocal,
+        (None, None, Some(_)) => MergeState::AddedRemote,
+        (None, Some(_), None) => MergeState::DeletedBoth,
+        (None, None, None) => unreachable!(),
+    };
+    has_conflict |= matches!(state, MergeState::AddAddConflict | MergeState::ModifyDeleteConflict)
+        || children.iter().any(|c| c.has_conflict);
+
+    let size = |e: Option<&DirEntry>| e.and_then(|e| (!e.is_dir).then_some(e.size));
+    MergeEntry {
+        name: name.to_string(),
+        rel_path,
+        is_dir,
+        local_kind: local.map(|e| e.kind),
+        base_kind: base.map(|e| e.kind),
+        remote_kind: remote.map(|e| e.kind),
+        local_size: size(local),
+        base_size: size(base),
+        remote_size: size(remote),
+        state,
+        conflict_count,
+        has_conflict,
+        children,
+    }
+}
+
+/// Align the local, base and remote listings of one directory
+fn merge_three_trees(roots: &MergeRoots, local: &[DirEntry], base: &[DirEntry], remote: &[DirEntry]) -> Vec<MergeEntry> {
+    let maps = [local, base, remote].map(|entries| {
+        entries
+            .iter()
+            .map(|e| (e.name.as_str(), e))
+            .collect::<HashMap<&str, &DirEntry>>()
+    });
+    let mut names: Vec<&str> = maps
+        .iter()
+        .flat_map(|m| m.keys().copied())
+        .collect::<std::collections::HashSet<_>>()
+        .into_iter()
+        .collect();
+    names.sort_unstable();
+
+    let mut result: Vec<MergeEntry> = names
+        .into_par_iter()
+        .map(|name| {
+            let [l, b, r] = [0, 1, 2].map(|i| maps[i].get(name).copied());
+            merge_three_entry(roots, name, l, b, r)
         })
         .collect();
 
-    // Sort: dirs first, then by name
-    entries.par_sort_by(|a, b| {
+    // Sort: directories first, then by name
+    result.par_sort_by(|a, b| {
         if a.is_dir != b.is_dir {
-            return a.is_dir.cmp(&b.is_dir).reverse(); // dirs first
+            return b.is_dir.cmp(&a.is_dir);
         }
         a.name.to_lowercase().cmp(&b.name.to_lowercase())
     });
 
-    Ok(entries)
-}
+    result
+}
+
+/// Count statistics from merge entries
+fn count_merge_stats(entries: &[MergeEntry]) -> MergeStats {
+    let mut stats = MergeStats {
+        unchanged: 0,
+        local_changes: 0,
+        remote_changes: 0,
+        both_changes: 0,
+        conflicts: 0,
+        total_files: 0,
+    };
+
+    fn count_recursive(entries: &[MergeEntry], stats: &mut MergeStats) {
+        for entry in entries {
+            if entry.is_dir {
+                count_recursive(&entry.children, stats);
+                continue;
+            }
+            stats.total_files += 1;
+            match entry.state.sides() {
+                (false, false) => stats.unchanged += 1,
+                (true, false) => stats.local_changes += 1,
+                (false, true) => stats.remote_changes += 1,
+                (true, true) => stats.both_changes += 1,
+            }
+            if entry.has_conflict {
+                stats.conflicts += 1;
+            }
+        }
+    }
+
+    count_recursive(entries, &mut stats);
+    stats
+}
+
+/// Compare a local and a remote directory against their common base. Files
+/// changed on both sides are merged in memory to find real conflicts; nothing
+/// is written.
+fn merge_three_directories(
+    local_path: &str,
+    base_path: &str,
+    remote_path: &str,
+    options: &CompareOptions,
+    merge_options: &MergeOptions,
+) -> Result<DirMergeResult, String> {
+    let start = Instant::now();
+    let cancel = CancelToken::default();
+    let mut trees = Vec::new();
+    for path in [local_path, base_path, remote_path] {
+        let root = Path::new(path);
+        if !root.is_dir() {
+            return Err(format!("{} is not a directory", path));
+        }
+        let rules = IgnoreRules::new(root, &options.ignore_patterns, options.scan.hidden_files)?;
+        let walk = WalkState::new(&options.scan, root);
+        trees.push(build_dir_tree(root, root, &rules, &walk, &cancel)?);
+    }
+
+    let roots = MergeRoots {
+        local: Path::new(local_path),
+        base: Path::new(base_path),
+        remote: Path::new(remote_path),
+        method: options.method,
+        options: merge_options,
+    };
+    let entries = merge_three_trees(&roots, &trees[0], &trees[1], &trees[2]);
+    save_hash_cache();
+
+    let stats = count_merge_stats(&entries);
+    info!(
+        "merge_directories: {} files, {} conflicts in {:?}",
+        stats.total_files,
+        stats.conflicts,
+        start.elapsed()
+    );
+
+    Ok(DirMergeResult {
+        root_local: local_path.to_string(),
+        root_base: base_path.to_string(),
+        root_remote: remote_path.to_string(),
+        entries,
+        stats,
+    })
+}
+
+/// Three-way compare of a local and remote directory against their base
+#[tauri::command]
+async fn merge_directories(
+    local_path: String,
+    base_path: String,
+    remote_path: String,
+    options: Option<CompareOptions>,
+    merge_options: Option<MergeOptions>,
+) -> Result<DirMergeResult, String> {
+    tokio::task::spawn_blocking(move || {
+        merge_three_directories(
+            &local_path,
+            &base_path,
+            &remote_path,
+            &options.unwrap_or_default(),
+            &merge_options.unwrap_or_default(),
+        )
+    })
+    .await
+    .map_err(|e| format!("Merge task failed: {}", e))?
+}
+
+impl SyncAction {
+    /// Where the entry comes from (copies only) and the side it changes
+    fn roots<'a>(&self, left: &'a Path, right: &'a Path) -> (Option<&'a Path>, &'a Path) {
+        match self {
+            SyncAction::CopyToRight => (Some(left), right),
+            SyncAction::CopyToLeft => (Some(right), left),
+            SyncAction::DeleteLeft => (None, left),
+            SyncAction::DeleteRight => (None, right),
+        }
+    }
+
+    /// The delete on the side this action changes
+    fn delete_at_target(&self) -> SyncAction {
+        match self {
+            SyncAction::CopyToRight | SyncAction::DeleteRight => SyncAction::DeleteRight,
+            SyncAction::CopyToLeft | SyncAction::DeleteLeft => SyncAction::DeleteLeft,
+        }
+    }
+}
+
+/// Join an aligned `rel_path` onto `root`, refusing paths that could leave it
+fn resolve_rel_path(root: &Path, rel_path: &str) -> Result<PathBuf, String> {
+    let rel = Path::new(rel_path);
+    let is_plain = rel.components().all(|c| matches!(c, std::path::Component::Normal(_)));
+    if rel_path.is_empty() || !is_plain {
+        return Err(format!("Invalid relative path: {:?}", rel_path));
+    }
+    Ok(root.join(rel))
+}
+
+/// Total size of the files at or under `path`, without following links
+fn tree_size(path: &Path) -> u64 {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::read_dir(path)
+            .map(|entries| entries.flatten().map(|e| tree_size(&e.path())).sum())
+            .unwrap_or(0),
+        Ok(meta) if meta.is_file() => meta.len(),
+        _ => 0,
+    }
+}
+
+/// Remove a file, link or whole directory without following links
+fn remove_entry(path: &Path) -> std::io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Copy a file, link or directory tree to `to`, which must not exist.
+/// Links are recreated rather than followed.
+fn copy_tree(from: &Path, to: &Path) -> std::io::Result<()> {
+    let meta = fs::symlink_metadata(from)?;
+    if meta.is_dir() {
+        fs::create_dir(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        fs::set_permissions(to, meta.permissions())
+    } else if meta.file_type().is_symlink() {
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(fs::read_link(from)?, to)
+        }
+        #[cfg(not(unix))]
+        {
+            fs::copy(from, to).map(|_| ())
+        }
+    } else {
+        fs::copy(from, to).map(|_| ())
+    }
+}
+
+/// Copy `from` over `to`. The copy is staged next to the target and renamed
+/// into place, so a failure part-way leaves the old target untouched.
+fn replace_with_copy(from: &Path, to: &Path) -> Result<(), String> {
+    let name = to
+        .file_name()
+        .ok_or_else(|| format!("Invalid target: {}", to.display()))?
+        .to_string_lossy();
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create directory: {}", e))?;
+    }
+
+    let staging = to.with_file_name(format!(".{}.diffvibe-sync", name));
+    let _ = remove_entry(&staging);
+    if let Err(e) = copy_tree(from, &staging) {
+        let _ = remove_entry(&staging);
+        return Err(format!("Failed to copy: {}", e));
+    }
+
+    // Move the old target aside until the new one is in place
+    let aside = to.with_file_name(format!(".{}.diffvibe-old", name));
+    let replacing = fs::symlink_metadata(to).is_ok();
+    if replacing {
+        let _ = remove_entry(&aside);
+        if let Err(e) = fs::rename(to, &aside) {
+            let _ = remove_entry(&staging);
+            return Err(format!("Failed to replace {}: {}", to.display(), e));
+        }
+    }
+    if let Err(e) = fs::rename(&staging, to) {
+        if replacing {
+            let _ = fs::rename(&aside, to);
+        }
+        let _ = remove_entry(&staging);
+        return Err(format!("Failed to replace {}: {}", to.display(), e));
+    }
+    if replacing {
+        let _ = remove_entry(&aside);
+    }
+    Ok(())
+}
+
+/// Directories under `target` that copying `source` over it would replace with
+/// a non-directory, as rel_paths below `rel_path`
+fn replaced_dirs(source: &Path, target: &Path, rel_path: &Path, out: &mut Vec<String>) {
+    let (Ok(source_meta), Ok(target_meta)) = (fs::symlink_metadata(source), fs::symlink_metadata(target)) else {
+        return;
+    };
+    if !target_meta.is_dir() {
+        return;
+    }
+    if !source_meta.is_dir() {
+        out.push(rel_path.to_string_lossy().to_string());
+        return;
+    }
+    for entry in fs::read_dir(source).into_iter().flatten().flatten() {
+        let name = entry.file_name();
+        replaced_dirs(&entry.path(), &target.join(&name), &rel_path.join(&name), out);
+    }
+}
+
+/// Turn the selected entries into a sync plan. Entries inside a selected
+/// directory are covered by it and left out; one that asks for a different
+/// action is a conflict and the whole plan is rejected. Copies merge into existing directories; any target directory a
+/// copy would replace with a file is planned as an explicit delete first.
+/// Every source and delete target must exist.
+fn plan_sync(left_root: &Path, right_root: &Path, items: &[SyncItem]) -> Result<Vec<SyncOperation>, String> {
+    // Component order puts every entry right after its selected ancestors
+    let mut items: Vec<&SyncItem> = items.iter().collect();
+    items.sort_by(|a, b| Path::new(&a.rel_path).cmp(Path::new(&b.rel_path)));
+
+    let mut operations: Vec<SyncOperation> = Vec::new();
+    let mut covering: Option<&SyncItem> = None;
+    for item in items {
+        if let Some(kept) = covering.filter(|kept| Path::new(&item.rel_path).starts_with(&kept.rel_path)) {
+            if kept.action != item.action {
+                return Err(format!("Conflicting sync actions for {} and {}", kept.rel_path, item.rel_path));
+            }
+            continue;
+        }
+        covering = Some(item);
+
+        let (source_root, target_root) = item.action.roots(left_root, right_root);
+        let target = resolve_rel_path(target_root, &item.rel_path)?;
+        let Some(source_root) = source_root else {
+            fs::symlink_metadata(&target).map_err(|_| format!("{} does not exist", target.display()))?;
+            operations.push(SyncOperation {
+                rel_path: item.rel_path.clone(),
+                action: item.action,
+                is_dir: target.is_dir() && !target.is_symlink(),
+                replaces: false,
+                bytes: tree_size(&target),
+            });
+            continue;
+        };
+
+        let source = resolve_rel_path(source_root, &item.rel_path)?;
+        let meta = fs::symlink_metadata(&source).map_err(|_| format!("{} does not exist", source.display()))?;
+        let mut replaced = Vec::new();
+        replaced_dirs(&source, &target, Path::new(&item.rel_path), &mut replaced);
+        for rel_path in replaced {
+            let path = resolve_rel_path(target_root, &rel_path)?;
+            operations.push(SyncOperation {
+                rel_path,
+                action: item.action.delete_at_target(),
+                is_dir: true,
+                replaces: false,
+                bytes: tree_size(&path),
+            });
+        }
+        operations.push(SyncOperation {
+            rel_path: item.rel_path.clone(),
+            action: item.action,
+            is_dir: meta.is_dir(),
+            replaces: fs::symlink_metadata(&target).is_ok(),
+            bytes: tree_size(&source),
+        });
+    }
+    Ok(operations)
+}
+
+/// Copy `from` onto `to`, merging into an existing directory: entries only at
+/// the target are kept, and everything else is replaced one entry at a time
+fn merge_copy(from: &Path, to: &Path) -> Result<(), String> {
+    let meta = fs::symlink_metadata(from).map_err(|e| format!("Failed to read {}: {}", from.display(), e))?;
+    let target_is_dir = fs::symlink_metadata(to).is_ok_and(|m| m.is_dir());
+    if !meta.is_dir() {
+        // The plan deletes such directories first; don't drop one it didn't show
+        if target_is_dir {
+            return Err(format!("{} is a directory", to.display()));
+        }
+        return replace_with_copy(from, to);
+    }
+    if !target_is_dir {
+        return replace_with_copy(from, to);
+    }
+    let entries = fs::read_dir(from).map_err(|e| format!("Failed to read {}: {}", from.display(), e))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| e.to_string())?;
+        merge_copy(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    fs::set_permissions(to, meta.permissions()).map_err(|e| format!("Failed to set permissions on {}: {}", to.display(), e))
+}
+
+/// Carry out one planned operation
+fn apply_sync_operation(left_root: &Path, right_root: &Path, op: &SyncOperation) -> Result<(), String> {
+    let (source_root, target_root) = op.action.roots(left_root, right_root);
+    let target = resolve_rel_path(target_root, &op.rel_path)?;
+    match source_root {
+        Some(source_root) => merge_copy(&resolve_rel_path(source_root, &op.rel_path)?, &target),
+        None => remove_entry(&target).map_err(|e| format!("Failed to delete {}: {}", target.display(), e)),
+    }
+}
+
+/// Plan the selected operations and, unless `dry_run`, run them in order.
+/// A failed operation is reported in its result and the rest still run.
+fn sync_directory_entries(
+    left_path: &str,
+    right_path: &str,
+    items: &[SyncItem],
+    dry_run: bool,
+    on_progress: impl Fn(SyncProgress),
+) -> Result<SyncReport, String> {
+    let left_root = Path::new(left_path);
+    let right_root = Path::new(right_path);
+    if !left_root.is_dir() {
+        return Err(format!("{} is not a directory", left_path));
+    }
+    if !right_root.is_dir() {
+        return Err(format!("{} is not a directory", right_path));
+    }
+
+    let operations = plan_sync(left_root, right_root, items)?;
+    let mut results = Vec::new();
+    if !dry_run {
+        let total = operations.len();
+        for (i, op) in operations.iter().enumerate() {
+            on_progress(SyncProgress { done: i, total, rel_path: op.rel_path.clone() });
+            let error = apply_sync_operation(left_root, right_root, op).err();
+            if let Some(e) = &error {
+                debug!("Sync of {} failed: {}", op.rel_path, e);
+            }
+            results.push(SyncItemResult { rel_path: op.rel_path.clone(), action: op.action, error });
+        }
+        on_progress(SyncProgress { done: total, total, rel_path: String::new() });
+        info!(
+            "sync_directories: {} of {} operations succeeded",
+            results.iter().filter(|r| r.error.is_none()).count(),
+            total
+        );
+    }
+
+    Ok(SyncReport {
+        root_left: left_path.to_string(),
+        root_right: right_path.to_string(),
+        dry_run,
+        operations,
+        results,
+    })
+}
+
+/// Copy or delete the selected entries of two compared directories. With
+/// `dry_run`, only the plan is returned; otherwise `sync-progress` events are
+/// emitted as each operation starts.
+#[tauri::command]
+async fn sync_directories(
+    window: tauri::Window,
+    left_path: String,
+    right_path: String,
+    items: Vec<SyncItem>,
+    dry_run: Option<bool>,
+) -> Result<SyncReport, String> {
+    tokio::task::spawn_blocking(move || {
+        sync_directory_entries(&left_path, &right_path, &items, dry_run.unwrap_or(false), |progress| {
+            let _ = window.emit("sync-progress", progress);
+        })
+    })
+    .await
+    .map_err(|e| format!("Sync task failed: {}", e))?
+}
+
+/// Trash batches older than this are purged on the next delete
+const TRASH_MAX_AGE_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// Name of the batch record inside each trash batch directory
+const TRASH_MANIFEST: &str = "manifest.json";
+
+fn trash_dir() -> Result<PathBuf, String> {
+    // Tests never touch the user's data directory
+    if cfg!(test) {
+        return Ok(std::env::temp_dir().join("diffvibe_test_trash"));
+    }
+    dirs::data_local_dir()
+        .map(|dir| dir.join("diffvibe").join("trash"))
+        .ok_or_else(|| "No local data directory for the trash".to_string())
+}
+
+fn trash_batch_dir(id: &str) -> Result<PathBuf, String> {
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(format!("Invalid trash batch id: {:?}", id));
+    }
+    Ok(trash_dir()?.join(id))
+}
+
+/// How `move_entry` failed
+#[derive(Debug)]
+enum MoveFailure {
+    /// Nothing was moved; the source is intact
+    NotMoved(std::io::Error),
+    /// The destination holds a complete copy, but only part of the source
+    /// could be removed
+    SourceLeft(std::io::Error),
+}
+
+impl std::fmt::Display for MoveFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MoveFailure::NotMoved(e) => write!(f, "{}", e),
+            MoveFailure::SourceLeft(e) => write!(f, "copied, but the original could not be fully removed: {}", e),
+        }
+    }
+}
+
+/// Move a file or directory, copying and then removing the source when the
+/// destination is on another filesystem
+fn move_entry(from: &Path, to: &Path) -> Result<(), MoveFailure> {
+    match fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            if let Err(e) = copy_tree(from, to) {
+                let _ = remove_entry(to);
+                return Err(MoveFailure::NotMoved(e));
+            }
+            remove_entry(from).map_err(MoveFailure::SourceLeft)
+        }
+        Err(e) => Err(MoveFailure::NotMoved(e)),
+    }
+}
+
+/// Copy whatever `from` has that `to` lacks, leaving existing entries alone.
+/// Makes a partly removed source whole again from its complete copy.
+fn fill_missing(from: &Path, to: &Path) -> std::io::Result<()> {
+    match fs::symlink_metadata(to) {
+        Err(_) => copy_tree(from, to),
+        Ok(meta) if meta.is_dir() && fs::symlink_metadata(from)?.is_dir() => {
+            for entry in fs::read_dir(from)? {
+                let entry = entry?;
+                fill_missing(&entry.path(), &to.join(entry.file_name()))?;
+            }
+            Ok(())
+        }
+        Ok(_) => Ok(()),
+    }
+}
+
+fn write_trash_manifest(batch_dir: &Path, batch: &TrashBatch) -> Result<(), String> {
+    let manifest = serde_json::to_vec(batch).map_err(|e| e.to_string())?;
+    fs::write(batch_dir.join(TRASH_MANIFEST), manifest).map_err(|e| format!("Failed to write trash manifest: {}", e))
+}
+
+/// Remove batches older than `TRASH_MAX_AGE_SECS`
+fn prune_trash(trash: &Path) {
+    let Ok(batches) = fs::read_dir(trash) else {
+        return;
+    };
+    let cutoff = unix_now_secs().saturating_sub(TRASH_MAX_AGE_SECS);
+    for batch in batches.flatten() {
+        let expired = fs::read(batch.path().join(TRASH_MANIFEST))
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<TrashBatch>(&bytes).ok())
+            .is_some_and(|b| b.created < cutoff);
+        if expired {
+            debug!("Purging trash batch {}", batch.path().display());
+            let _ = fs::remove_dir_all(batch.path());
+        }
+    }
+}
+
+/// Move the entries at `rel_paths` under `root` into a new trash batch.
+/// Entries inside another selected directory go with it. Either every entry
+/// is moved or, on failure, the ones already moved are put back.
+fn trash_entries(root_path: &str, rel_paths: &[String]) -> Result<TrashBatch, String> {
+    let root = Path::new(root_path);
+    if !root.is_dir() {
+        return Err(format!("{} is not a directory", root_path));
+    }
+
+    let mut rel_paths: Vec<&String> = rel_paths.iter().collect();
+    rel_paths.sort();
+    rel_paths.dedup();
+    let mut selected: Vec<&String> = Vec::new();
+    for rel_path in rel_paths {
+        if !selected.iter().any(|s| Path::new(rel_path).starts_with(s)) {
+            selected.push(rel_path);
+        }
+    }
+    let mut sources = Vec::new();
+    for rel_path in &selected {
+        let path = resolve_rel_path(root, rel_path)?;
+        let meta = fs::symlink_metadata(&path).map_err(|_| format!("{} does not exist", path.display()))?;
+        sources.push((path, meta.is_dir()));
+    }
+
+    let trash = trash_dir()?;
+    prune_trash(&trash);
+    let created = unix_now_secs();
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+    let id = format!("{}-{:09}-{}", created, nanos, std::process::id());
+    let batch_dir = trash_batch_dir(&id)?;
+    fs::create_dir_all(&batch_dir).map_err(|e| format!("Failed to create trash: {}", e))?;
+
+    let mut batch = TrashBatch { id, root: root_path.to_string(), created, entries: Vec::new() };
+    for (i, (path, is_dir)) in sources.iter().enumerate() {
+        let trashed = batch_dir.join(i.to_string());
+        let entry = TrashedEntry {
+            rel_path: selected[i].clone(),
+            is_dir: *is_dir,
+            trashed_path: trashed.to_string_lossy().to_string(),
+        };
+        match move_entry(path, &trashed) {
+            Ok(()) => {
+                batch.entries.push(entry);
+                // Kept current so a crash part-way still leaves a restorable batch
+                write_trash_manifest(&batch_dir, &batch)?;
+            }
+            Err(failure) => {
+                let stranded = roll_back_trash(&batch, &sources, path, entry, &failure);
+                let error = format!("Failed to delete {}: {}", path.display(), failure);
+                if stranded.is_empty() {
+                    let _ = fs::remove_dir_all(&batch_dir);
+                    return Err(error);
+                }
+                // The trash holds the only complete copy of these; keep them undoable
+                let count = stranded.len();
+                batch.entries = stranded;
+                write_trash_manifest(&batch_dir, &batch)?;
+                return Err(format!("{}; {} entries could not be put back and remain in trash batch {}", error, count, batch.id));
+            }
+        }
+    }
+
+    info!("delete_entries: moved {} entries from {} to the trash", batch.entries.len(), root_path);
+    Ok(batch)
+}
+
+/// Undo a failed `trash_entries`: put back the entries already moved and
+/// repair the one that failed. Returns the entries whose only complete copy
+/// is still in the trash.
+fn roll_back_trash(
+    batch: &TrashBatch,
+    sources: &[(PathBuf, bool)],
+    failed_path: &Path,
+    failed_entry: TrashedEntry,
+    failure: &MoveFailure,
+) -> Vec<TrashedEntry> {
+    let mut stranded = Vec::new();
+    if let MoveFailure::SourceLeft(_) = failure {
+        let trashed = Path::new(&failed_entry.trashed_path);
+        if fill_missing(trashed, failed_path).is_ok() {
+            let _ = remove_entry(trashed);
+        } else {
+            stranded.push(failed_entry);
+        }
+    }
+    for (entry, (original, _)) in batch.entries.iter().zip(sources) {
+        match move_entry(Path::new(&entry.trashed_path), original) {
+            // The original is whole again; leftovers in the trash go with the batch
+            Ok(()) | Err(MoveFailure::SourceLeft(_)) => {}
+            Err(MoveFailure::NotMoved(e)) => {
+                debug!("Could not put back {}: {}", original.display(), e);
+                stranded.push(entry.clone());
+            }
+        }
+    }
+    stranded
+}
+
+/// Put a trash batch's entries back where they were and return the ones
+/// restored. Nothing is restored if any original location has been taken
+/// since. The manifest is rewritten after each entry, so a restore that
+/// fails part-way can be retried for the rest.
+fn restore_trash_batch(id: &str) -> Result<TrashBatch, String> {
+    let batch_dir = trash_batch_dir(id)?;
+    let bytes = fs::read(batch_dir.join(TRASH_MANIFEST)).map_err(|_| format!("No deleted entries to restore for {}", id))?;
+    let mut batch: TrashBatch = serde_json::from_slice(&bytes).map_err(|e| format!("Corrupt trash manifest: {}", e))?;
+
+    // Entries whose trashed copy is gone were already restored
+    batch.entries.retain(|entry| fs::symlink_metadata(&entry.trashed_path).is_ok());
+
+    let root = Path::new(&batch.root);
+    let mut targets = Vec::new();
+    for entry in &batch.entries {
+        let original = resolve_rel_path(root, &entry.rel_path)?;
+        if fs::symlink_metadata(&original).is_ok() {
+            return Err(format!("Cannot restore {}: it already exists", original.display()));
+        }
+        targets.push(original);
+    }
+
+    let mut remaining = batch.clone();
+    for (entry, original) in batch.entries.iter().zip(&targets) {
+        if let Some(parent) = original.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("Failed to create directory: {}", e))?;
+        }
+        match move_entry(Path::new(&entry.trashed_path), original) {
+            // A partly removed trash copy is fine once the original is whole
+            Ok(()) | Err(MoveFailure::SourceLeft(_)) => {
+                remaining.entries.retain(|e| e.trashed_path != entry.trashed_path);
+                write_trash_manifest(&batch_dir, &remaining)?;
+            }
+            Err(failure) => return Err(format!("Failed to restore {}: {}", original.display(), failure)),
+        }
+    }
+    let _ = fs::remove_dir_all(&batch_dir);
+    info!("undo_delete: restored {} entries to {}", batch.entries.len(), batch.root);
+    Ok(batch)
+}
+
+/// Move entries of a compared directory, given by their aligned `rel_path`,
+/// to DiffVibe's trash
+#[tauri::command]
+async fn delete_entries(root: String, rel_paths: Vec<String>) -> Result<TrashBatch, String> {
+    tokio::task::spawn_blocking(move || trash_entries(&root, &rel_paths))
+        .await
+        .map_err(|e| format!("Delete task failed: {}", e))?
+}
+
+/// Restore the entries removed by one `delete_entries` call
+#[tauri::command]
+async fn undo_delete(batch_id: String) -> Result<TrashBatch, String> {
+    tokio::task::spawn_blocking(move || restore_trash_batch(&batch_id))
+        .await
+        .map_err(|e| format!("Restore task failed: {}", e))?
+}
+
+/// Release builds on Windows have no console of their own (see main.rs), so
+/// headless modes write to the console of the shell that started them
+#[cfg(windows)]
+fn attach_parent_console() {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn AttachConsole(process_id: u32) -> i32;
+    }
+    const ATTACH_PARENT_PROCESS: u32 = u32::MAX;
+    // Fails harmlessly when started without a console, e.g. from Explorer
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
+
+#[cfg(not(windows))]
+fn attach_parent_console() {}
+
+/// Initialize tracing with environment-based filtering.
+/// Set RUST_LOG=debug for verbose logging, RUST_LOG=info for normal.
+/// Logs go to stderr so headless output on stdout stays clean.
+fn init_tracing(default_filter: &str) {
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(default_filter))
+        )
+        .with_target(false)
+        .with_thread_ids(false)
+        .with_file(false)
+        .with_line_number(false)
+        .compact()
+        .init();
+}
+
+#[cfg_attr(mobile, tauri::mobile_entry_point)]
+pub fn run() {
+    // Parse CLI args before starting Tauri (headless modes exit here)
+    parse_cli_args();
+
+    init_tracing("info");
+    info!("DiffVibe starting...");
+
+    tauri::Builder::default()
+        .plugin(tauri_plugin_dialog::init())
+        .plugin(tauri_plugin_fs::init())
+        .plugin(tauri_plugin_opener::init())
+        .plugin(tauri_plugin_store::Builder::default().build())
+        .invoke_handler(tauri::generate_handler![read_file, write_file, copy_file, copy_dir, file_exists, is_directory, compute_diff, compute_diff_files, compute_three_way_diff, compute_image_diff_mask, get_cli_args, exit_app, compare_directories, merge_directories, sync_directories, delete_entries, undo_delete, cancel_comparison, clear_hash_cache, scan_directory, scan_directory_lazy, expand_directory, get_diff_stats, compare_directories_async])
+        .run(tauri::generate_context!())
+        .expect("error while running tauri application");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_directories() {
+        use std::fs;
+
+        // Create temp test directories
+        let temp = std::env::temp_dir().join("diffvibe_test");
+        let left = temp.join("left");
+        let right = temp.join("right");
+
+        // Clean up any previous test
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(&left).unwrap();
+        fs::create_dir_all(&right).unwrap();
+
+        // Same file
+        fs::write(left.join("same.txt"), "same content").unwrap();
+        fs::write(right.join("same.txt"), "same content").unwrap();
+
+        // Modified file
+        fs::write(left.join("modified.txt"), "left version").unwrap();
+        fs::write(right.join("modified.txt"), "right version").unwrap();
+
+        // Left only
+        fs::write(left.join("left-only.txt"), "only left").unwrap();
+
+        // Right only
+        fs::write(right.join("right-only.txt"), "only right").unwrap();
+
+        let result = compare_directories(
+            left.to_str().unwrap(),
+            right.to_str().unwrap(),
+            None,
+        ).unwrap();
+
+        assert_eq!(result.stats.identical, 1);
+        assert_eq!(result.stats.modified, 1);
+        assert_eq!(result.stats.left_only, 1);
+        assert_eq!(result.stats.right_only, 1);
+        assert_eq!(result.stats.total_files, 4);
+
+        // Clean up
+        let _ = fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_three_way_all_same() {
+        let base = "line 1\nline 2";
+        let local = "line 1\nline 2";
+        let remote = "line 1\nline 2";
+
+        let result = compute_three_way_diff(base, local, remote, None);
+
+        assert_eq!(result.conflict_count, 0);
+        assert_eq!(result.merged_content, "line 1\nline 2");
+    }
+
+    #[test]
+    fn test_three_way_with_conflict() {
+        let base = "original line";
+        let local = "local change";
+        let remote = "remote change";
+
+        let result = compute_three_way_diff(base, local, remote, None);
+
+        assert_eq!(result.conflict_count, 1);
+        assert!(result.merged_content.contains("<<<<<<< LOCAL"));
+        assert!(result.merged_content.contains("======="));
+        assert!(result.merged_content.contains(">>>>>>> REMOTE"));
+    }
+
+    #[test]
+    fn test_three_way_same_change() {
+        let base = "original";
+        let local = "changed";
+        let remote = "changed";
+
+        let result = compute_three_way_diff(base, local, remote, None);
+
+        assert_eq!(result.conflict_count, 0);
+        assert_eq!(result.merged_content, "changed");
+    }
+
+    #[test]
+    fn test_three_way_local_only() {
+        let base = "line 1\nline 2";
+        let local = "line 1\nmodified";  // Local changed line 2
+        let remote = "line 1\nline 2";   // Remote unchanged
+
+        let result = compute_three_way_diff(base, local, remote, None);
+
+        assert_eq!(result.conflict_count, 0);
+        assert!(result.merged_content.contains("modified"));
+        assert!(!result.merged_content.contains("line 2")); // Base line should be replaced
+    }
+
+    #[test]
+    fn test_three_way_remote_only() {
+        let base = "line 1\nline 2";
+        let local = "line 1\nline 2";    // Local unchanged
+        let remote = "line 1\nchanged";  // Remote changed line 2
+
+        let result = compute_three_way_diff(base, local, remote, None);
+
+        assert_eq!(result.conflict_count, 0);
+        assert!(result.merged_content.contains("changed"));
+        assert!(!result.merged_content.contains("line 2")); // Base line should be replaced
+    }
+
+    #[test]
+    fn test_three_way_realistic() {
+        // Simulate a more realistic scenario
+        let base = "// Header
+function foo() {
+    return 1;
+}
+
+function bar() {
+    return 2;
+}";
+
+        // Local adds logging to foo
+        let local = "// Header
+function foo() {
+    console.log('foo called');
+    return 1;
+}
+
+function bar() {
+    return 2;
+}";
+
+        // Remote changes bar's return value
+        let remote = "// Header
+function foo() {
+    return 1;
+}
+
+function bar() {
+    return 42;
+}";
+
+        let result = compute_three_way_diff(base, local, remote, None);
+
+        println!("=== MERGED OUTPUT ===");
+        println!("{}", result.merged_content);
+        println!("=== CONFLICTS: {} ===", result.conflict_count);
+
+        // Should have both changes merged without conflict
+        assert_eq!(result.conflict_count, 0, "Should have no conflicts");
+        assert!(result.merged_content.contains("console.log"), "Should have local's logging");
+        assert!(result.merged_content.contains("return 42"), "Should have remote's return value");
+    }
+
+    #[test]
+    fn test_three_way_chunks() {
+        let base = "a\nb\nc\nd";
+        let local = "a\nB\nc\nd";
+        let remote = "a\nb\nc\nD";
+
+        let result = compute_three_way_diff(base, local, remote, None);
+
+        let summary: Vec<_> = result
+            .chunks
+            .iter()
+            .map(|c| (c.chunk_type.clone(), c.base_start, c.base_count))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (ChunkType::Equal, 0, 1),
+                (ChunkType::LocalOnly, 1, 1),
+                (ChunkType::Equal, 2, 1),
+                (ChunkType::RemoteOnly, 3, 1),
+            ]
+        );
+        assert_eq!(result.chunks[1].local_lines, vec!["B"]);
+        assert_eq!(result.chunks[1].remote_lines, vec!["b"]);
+        assert_eq!(result.chunks[3].remote_lines, vec!["D"]);
+        assert_eq!(result.merged_content, "a\nB\nc\nD");
+    }
+
+    #[test]
+    fn test_three_way_conflict_chunk() {
+        let result = compute_three_way_diff("keep\nold", "keep\nmine", "keep\ntheirs", None);
+
+        let conflicts: Vec<_> = result
+            .chunks
+            .iter()
+            .filter(|c| c.chunk_type == ChunkType::Conflict)
+            .collect();
+        assert_eq!(conflicts.len(), result.conflict_count);
+        assert_eq!(conflicts[0].local_lines, vec!["mine"]);
+        assert_eq!(conflicts[0].remote_lines, vec!["theirs"]);
+        assert_eq!(result.chunks.iter().map(|c| c.base_count).sum::<usize>(), 2);
+    }
+
+    #[test]
+    fn test_three_way_replace_vs_delete_conflicts() {
+        let base = "1\n2\n3\n4\n5\n6";
+        let local = "1\n2\n3\n4\nfive\n6"; // Local replaces line 5
+        let remote = "1\n2\n3\n4\n6"; // Remote deletes it
+
+        let result = compute_three_way_diff(base, local, remote, None);
+
+        assert_eq!(result.conflict_count, 1);
+        let conflict = result.chunks.iter().find(|c| c.chunk_type == ChunkType::Conflict).unwrap();
+        assert_eq!((conflict.base_start, conflict.base_count), (4, 1));
+        assert_eq!(conflict.local_lines, vec!["five"]);
+        assert!(conflict.remote_lines.is_empty());
+    }
+
+    #[test]
+    fn test_three_way_both_replace_same_line() {
+        let base = "a\nb\nc";
+
+        let different = compute_three_way_diff(base, "a\nB1\nc", "a\nB2\nc", None);
+        assert_eq!(different.conflict_count, 1);
+        assert_eq!(different.merged_content, "a\n<<<<<<< LOCAL\nB1\n=======\nB2\n>>>>>>> REMOTE\nc");
+
+        let same = compute_three_way_diff(base, "a\nB\nc", "a\nB\nc", None);
+        assert_eq!(same.conflict_count, 0);
+        assert_eq!(same.chunks[1].chunk_type, ChunkType::BothSame);
+        assert_eq!(same.merged_content, "a\nB\nc");
+    }
+
+    #[test]
+    fn test_three_way_preserves_crlf_and_whitespace() {
+        let base = "a\r\nb \r\nc\r\n";
+        let local = "a\r\nb \r\nc changed  \r\n";
+        let remote = "A\r\nb \r\nc\r\n";
+
+        let result = compute_three_way_diff(base, local, remote, None);
+
+        assert_eq!(result.merged_content, "A\r\nb \r\nc changed  \r\n");
+        assert_eq!(result.line_ending, "crlf");
+        assert!(result.final_newline);
+        assert!(result.line_ending_mismatch.is_none());
+    }
+
+    #[test]
+    fn test_three_way_line_ending_mismatch() {
+        // Local converted to CRLF, remote dropped the final newline
+        let base = "a\nb\n";
+        let local = "a\r\nb\r\n";
+        let remote = "a\nb";
+
+        let result = compute_three_way_diff(base, local, remote, None);
+
+        assert_eq!(result.conflict_count, 0);
+        assert_eq!(result.merged_content, "a\r\nb");
+        assert!(!result.final_newline);
+        let note = result.line_ending_mismatch.unwrap();
+        assert!(note.contains("line endings differ"));
+        assert!(note.contains("final newline differs"));
+    }
+
+    #[test]
+    fn test_three_way_conflict_styles() {
+        let base = "start\nold\nend\n";
+        let local = "start\nsame\nmine\nend\n";
+        let remote = "start\nsame\ntheirs\nend\n";
+
+        let mut options = MergeOptions {
+            conflict_style: ConflictStyle::Diff3,
+            local_label: "main".to_string(),
+            remote_label: "feature".to_string(),
+            ..MergeOptions::default()
+        };
+        let diff3 = compute_three_way_diff(base, local, remote, Some(options.clone()));
+        assert_eq!(
+            diff3.merged_content,
+            "start\n<<<<<<< main\nsame\nmine\n||||||| BASE\nold\n=======\nsame\ntheirs\n>>>>>>> feature\nend\n"
+        );
+
+        options.conflict_style = ConflictStyle::Zdiff3;
+        options.marker_size = 3;
+        let zdiff3 = compute_three_way_diff(base, local, remote, Some(options));
+        assert_eq!(
+            zdiff3.merged_content,
+            "start\nsame\n<<< main\nmine\n||| BASE\nold\n===\ntheirs\n>>> feature\nend\n"
+        );
+        assert_eq!(zdiff3.conflict_count, 1);
+    }
+
+    #[test]
+    fn test_auto_merge_files() {
+        use std::fs;
+
+        let temp = std::env::temp_dir().join("diffvibe_auto_merge_test");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(&temp).unwrap();
+
+        let path = |name: &str| temp.join(name).to_string_lossy().to_string();
+        fs::write(path("base"), "a\nb\nc\n").unwrap();
+        fs::write(path("local"), "A\nb\nc\n").unwrap();
+        fs::write(path("remote"), "a\nb\nC\n").unwrap();
+        fs::write(path("remote2"), "X\nb\nc\n").unwrap();
+        let options = MergeOptions::default();
+
+        // Clean merge writes the merged file
+        let conflicts =
+            auto_merge_files(&path("local"), &path("base"), &path("remote"), Some(&path("out/clean")), &options)
+                .unwrap();
+        assert_eq!(conflicts, 0);
+        assert_eq!(fs::read_to_string(path("out/clean")).unwrap(), "A\nb\nC\n");
+
+        // Conflicting merge still writes the file, with markers
+        let conflicts =
+            auto_merge_files(&path("local"), &path("base"), &path("remote2"), Some(&path("out/conflict")), &options)
+                .unwrap();
+        assert_eq!(conflicts, 1);
+        assert!(fs::read_to_string(path("out/conflict")).unwrap().starts_with("<<<<<<< LOCAL\nA\n"));
+
+        assert!(auto_merge_files(&path("local"), &path("missing"), &path("remote"), None, &options).is_err());
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_unified_diff_format() {
+        let left = "one\ntwo\nthree\nfour\nfive\nsix\nseven\n";
+        let right = "one\ntwo\nTHREE\nfour\nfive\nsix\nseven\neight\n";
+
+        let diff = compute_diff(left, right, None);
+        let output = format_unified_diff(&diff, "a.txt", "b.txt", 1);
+
+        assert_eq!(
+            output,
+            "--- a.txt\n+++ b.txt\n\
+             @@ -2,3 +2,3 @@\n two\n-three\n+THREE\n four\n\
+             @@ -7 +7,2 @@\n seven\n+eight\n"
+        );
+    }
+
+    #[test]
+    fn test_unified_diff_no_trailing_newline() {
+        let diff = compute_diff("same\nend", "same\nend\n", None);
+        let output = format_unified_diff(&diff, "a", "b", 3);
+
+        assert_eq!(
+            output,
+            "--- a\n+++ b\n@@ -1,2 +1,2 @@\n same\n-end\n\\ No newline at end of file\n+end\n"
+        );
+        assert!(format_unified_diff(&compute_diff("x\n", "x\n", None), "a", "b", 3).is_empty());
+    }
+
+    #[test]
+    fn test_diff_ignore_whitespace_and_case() {
+        let left = "fn main() {\n    let x  = 1;\n    Foo();   \n}\n";
+        let right = "fn main() {\n  let x = 1;\n    foo();\n}\n";
+
+        let plain = compute_diff(left, right, None);
+        assert_eq!(plain.stats.deletions, 2);
+
+        let options = DiffOptions {
+            ignore_whitespace_amount: true,
+            ignore_case: true,
+            ..DiffOptions::default()
+        };
+        let result = compute_diff(left, right, Some(options));
+        assert_eq!((result.stats.additions, result.stats.deletions, result.stats.unchanged), (0, 0, 4));
+
+        // Original text and indices are kept for both sides
+        let line = &result.lines[1];
+        assert_eq!(line.value, "    let x  = 1;\n");
+        assert_eq!(line.new_value.as_deref(), Some("  let x = 1;\n"));
+        assert_eq!((line.old_index, line.new_index), (Some(1), Some(1)));
+        assert!(result.lines[0].new_value.is_none());
+
+        let all = DiffOptions { ignore_all_whitespace: true, ..DiffOptions::default() };
+        assert_eq!(compute_diff("a b\n", "ab\n", Some(all)).stats.unchanged, 1);
+    }
+
+    #[test]
+    fn test_diff_ignore_blank_lines() {
+        let left = "a\n\nb\nc\nd\ne\n";
+        let right = "a\nb\nc\n\nd\nE\n";
+        let options = DiffOptions { ignore_blank_lines: true, ..DiffOptions::default() };
+
+        let result = compute_diff(left, right, Some(options.clone()));
+
+        assert_eq!((result.stats.additions, result.stats.deletions), (1, 1));
+        let blank = result.lines.iter().find(|l| l.old_index == Some(1)).unwrap();
+        assert_eq!((blank.tag.as_str(), blank.new_index, blank.ignored), ("delete", None, true));
+        assert!(result.lines.iter().filter(|l| l.tag == "equal").all(|l| l.old_index.is_some() && l.new_index.is_some()));
+        let output = format_unified_diff(&result, "a", "b", 0);
+        assert_eq!(output, "--- a\n+++ b\n@@ -6 +6 @@\n-e\n+E\n");
+
+        // Blank lines inside a real change aren't ignored
+        let mixed = compute_diff("x\n", "\ny\n", Some(options));
+        assert_eq!((mixed.stats.additions, mixed.stats.deletions), (2, 1));
+        assert!(mixed.lines.iter().all(|l| !l.ignored));
+    }
+
+    #[test]
+    fn test_diff_inline_spans() {
+        let result = compute_diff("a\nlet total = count + 1;\nz\n", "a\nlet sum = count + 2;\nz\n", None);
+
+        let old = result.lines.iter().find(|l| l.tag == "delete").unwrap();
+        let new = result.lines.iter().find(|l| l.tag == "insert").unwrap();
+        let ranges = |spans: &[InlineSpan]| spans.iter().map(|s| (s.start, s.end)).collect::<Vec<_>>();
+
+        assert_eq!(ranges(&old.word_spans), vec![(4, 9), (20, 22)]);
+        assert_eq!(ranges(&new.word_spans), vec![(4, 7), (18, 20)]);
+        assert_eq!(&new.value[4..7], "sum");
+        assert!(!old.char_spans.is_empty());
+        assert!(result.lines[0].word_spans.is_empty());
+
+        // Nothing is added once the deadline has passed
+        let mut lines = result.lines;
+        for line in &mut lines {
+            line.word_spans.clear();
+            line.char_spans.clear();
+        }
+        add_inline_spans(&mut lines, Some(Instant::now()));
+        assert!(lines.iter().all(|l| l.word_spans.is_empty() && l.char_spans.is_empty()));
+    }
+
+    #[test]
+    fn test_diff_inline_spans_utf16_offsets() {
+        let result = compute_diff("🙂 old\n", "🙂 new\n", None);
+
+        let new = result.lines.iter().find(|l| l.tag == "insert").unwrap();
+        // The emoji is two UTF-16 code units, so "new" starts at 3
+        assert_eq!(new.word_spans, vec![InlineSpan { start: 3, end: 6 }]);
+    }
+
+    #[test]
+    fn test_diff_algorithms() {
+        // Myers aligns the shared closing braces; patience and histogram keep
+        // the new function as one inserted block
+        let left = "fn a() {\n    one();\n}\n";
+        let right = "fn b() {\n    two();\n}\n\nfn a() {\n    one();\n}\n";
+
+        for algorithm in [DiffAlgorithm::Patience, DiffAlgorithm::Histogram] {
+            let options = DiffOptions { algorithm, ..DiffOptions::default() };
+            let result = compute_diff(left, right, Some(options));
+            let tags: Vec<&str> = result.lines.iter().map(|l| l.tag.as_str()).collect();
+            assert_eq!(
+                tags,
+                vec!["insert", "insert", "insert", "insert", "equal", "equal", "equal"],
+                "{:?}",
+                algorithm
+            );
+        }
+
+        // Every algorithm must reproduce both sides
+        let left = "a\nb\nc\na\nb\nd\n";
+        let right = "b\na\nc\nd\nb\na\n";
+        for algorithm in [DiffAlgorithm::Myers, DiffAlgorithm::Patience, DiffAlgorithm::Lcs, DiffAlgorithm::Histogram] {
+            let options = DiffOptions { algorithm, ..DiffOptions::default() };
+            let result = compute_diff(left, right, Some(options));
+            let old: String = result.lines.iter().filter(|l| l.old_index.is_some()).map(|l| l.value.as_str()).collect();
+            let new: String = result.lines.iter().filter(|l| l.new_index.is_some()).map(|l| l.value.as_str()).collect();
+            assert_eq!((old.as_str(), new.as_str()), (left, right), "{:?}", algorithm);
+        }
+    }
+
+    #[test]
+    fn test_diff_moved_blocks() {
+        let helper = "fn helper() {\n    let total = compute();\n    total * 2\n}\n";
+        let left = format!("use std::fs;\n\n{}\nfn main() {{\n    run();\n}}\n", helper);
+        let right = format!("use std::fs;\n\nfn main() {{\n    run();\n}}\n\n{}", helper.replace("    ", "  "));
+
+        let result = compute_diff(&left, &right, None);
+
+        assert_eq!(result.moves.len(), 1);
+        let block = &result.moves[0];
+        assert_eq!((block.old_start, block.new_start, block.count), (2, 6, 3));
+        assert!(!block.exact);
+        let moved = result.lines.iter().filter(|l| l.move_index == Some(0)).count();
+        assert_eq!(moved, 6);
+
+        // Small edits within one hunk aren't moves
+        let edit = compute_diff("a\n    one two three four five\nb\n", "a\none two three four five\nb\n", None);
+        assert!(edit.moves.is_empty());
+
+        // Nothing is searched once the deadline has passed
+        let mut lines = compute_diff(&left, &right, None).lines;
+        assert!(detect_moved_blocks(&mut lines, Some(Instant::now())).is_empty());
+
+        // Lines repeated too often to anchor a move are skipped
+        let block = "let total = compute_everything();\n".repeat(3);
+        let filler: String = (0..10).map(|i| format!("filler line {}\n", i)).collect();
+        let once = compute_diff(&format!("{}{}", block, filler), &format!("{}{}", filler, block), None);
+        assert_eq!(once.moves.len(), 1);
+        let many = compute_diff(&format!("{}{}", block, filler), &format!("{}{}", filler, block.repeat(3)), None);
+        assert!(many.moves.is_empty());
+    }
+
+    #[test]
+    fn test_compare_directories_large_files() {
+        use std::fs;
+
+        let temp = std::env::temp_dir().join("diffvibe_large_file_test");
+        let left = temp.join("left");
+        let right = temp.join("right");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(&left).unwrap();
+        fs::create_dir_all(&right).unwrap();
+
+        // Same size, differing only in the last byte, well past 64KB
+        let mut content = vec![b'x'; 200_000];
+        fs::write(left.join("big.bin"), &content).unwrap();
+        *content.last_mut().unwrap() = b'y';
+        fs::write(right.join("big.bin"), &content).unwrap();
+        fs::write(left.join("same.bin"), &content).unwrap();
+        fs::write(right.join("same.bin"), &content).unwrap();
+
+        let (l, r) = (left.to_str().unwrap(), right.to_str().unwrap());
+        for method in [CompareMethod::Hash, CompareMethod::Bytes] {
+            let options = CompareOptions { method, ..Default::default() };
+            let result = compare_directories(l, r, Some(options)).unwrap();
+            assert_eq!((result.stats.identical, result.stats.modified), (1, 1), "{:?}", method);
+        }
+        let options = CompareOptions { method: CompareMethod::Size, ..Default::default() };
+        let size_only = compare_directories(l, r, Some(options)).unwrap();
+        assert_eq!(size_only.stats.identical, 2);
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_aligned_compare_checks_content() {
+        use std::fs;
+
+        let temp = std::env::temp_dir().join("diffvibe_aligned_content_test");
+        let left = temp.join("left");
+        let right = temp.join("right");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(left.join("sub")).unwrap();
+        fs::create_dir_all(right.join("sub")).unwrap();
+
+        // Same length, different content
+        fs::write(left.join("sub/edit.txt"), "hello world").unwrap();
+        fs::write(right.join("sub/edit.txt"), "hello World").unwrap();
+        fs::write(left.join("same.txt"), "same").unwrap();
+        fs::write(right.join("same.txt"), "same").unwrap();
+
+        let cancel = CancelToken::default();
+        let left_rules = IgnoreRules::new(&left, &[], HiddenFiles::Exclude).unwrap();
+        let right_rules = IgnoreRules::new(&right, &[], HiddenFiles::Exclude).unwrap();
+        let walk = WalkState::default();
+        let left_tree = build_dir_tree(&left, &left, &left_rules, &walk, &cancel).unwrap();
+        let right_tree = build_dir_tree(&right, &right, &right_rules, &walk, &cancel).unwrap();
+        let mut aligned = merge_directory_trees(&left_tree, &right_tree);
+        assert_eq!(count_aligned_stats(&aligned).identical, 2);
+
+        let progress = std::sync::Mutex::new(Vec::new());
+        refine_content_status(&mut aligned, &left, &right, CompareMethod::Hash, &cancel, |done, total| {
+            progress.lock().unwrap().push((done, total));
+        }).unwrap();
+
+        let stats = count_aligned_stats(&aligned);
+        assert_eq!((stats.identical, stats.modified), (1, 1));
+        let sub = aligned.iter().find(|e| e.name == "sub").unwrap();
+        assert!(matches!(sub.status, EntryStatus::Modified));
+        assert_eq!(progress.into_inner().unwrap().last(), Some(&(2, 2)));
+
+        let _ = fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_hash_cache_roundtrip_and_invalidation() {
+        let temp = std::env::temp_dir().join("diffvibe_hash_cache_test");
+        let _ = std::fs::remove_dir_all(&temp);
+        let cache_path = temp.join("hash-cache.json");
+
+        let old = FileStamp { size: 10, mtime_ns: 1_000_000_000_000, inode: Some(7) };
+        let mut cache = HashCache::load(Some(cache_path.clone()));
+        cache.insert("/a".to_string(), old.clone(), 42);
+        cache.insert("/b".to_string(), old.clone(), 43);
+
+        // Recently modified files aren't cached
+        let racy = FileStamp { mtime_ns: unix_now_secs() * 1_000_000_000, ..old.clone() };
+        cache.insert("/racy".to_string(), racy.clone(), 44);
+        assert_eq!(cache.get("/racy", &racy), None);
+
+        cache.save().unwrap();
+        let mut reloaded = HashCache::load(Some(cache_path));
+        assert_eq!(reloaded.get("/a", &old), Some(42));
+        assert!(!reloaded.dirty, "a recent entry isn't touched on a hit");
+
+        // Entries last used over a day ago are refreshed
+        let stale = unix_now_secs() - HASH_CACHE_TOUCH_SECS - 1;
+        reloaded.entries.get_mut("/a").unwrap().last_used = stale;
+        assert_eq!(reloaded.get("/a", &old), Some(42));
+        assert!(reloaded.dirty && reloaded.entries["/a"].last_used > stale);
+
+        // Any change to size, mtime or inode invalidates the entry
+        assert_eq!(reloaded.get("/a", &FileStamp { size: 11, ..old.clone() }), None);
+        assert_eq!(reloaded.get("/a", &FileStamp { inode: Some(8), ..old.clone() }), None);
+
+        reloaded.entries.get_mut("/b").unwrap().last_used = 0;
+        reloaded.evict_to(1);
+        assert!(reloaded.entries.contains_key("/a"));
+        assert!(!reloaded.entries.contains_key("/b"));
+
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+
+    #[test]
+    fn test_cancel_comparison() {
+        let registration = ComparisonRegistration::new(Some("cancel-test".to_string()));
+        assert!(!registration.token.is_cancelled());
+
+        assert_eq!(cancel_comparison("cancel-test"), Ok(true));
+        assert!(registration.token.is_cancelled());
+
+        let root = std::env::temp_dir();
+        let rules = IgnoreRules::new(&root, &[], HiddenFiles::Exclude).unwrap();
+        let result = build_dir_tree(&root, &root, &rules, &WalkState::default(), &registration.token);
+        assert_eq!(result.unwrap_err(), CANCELLED_ERROR);
+
+        drop(registration);
+        assert_eq!(cancel_comparison("cancel-test"), Ok(false));
+
+        // An older run finishing doesn't unregister a newer one with the same id
+        let old = ComparisonRegistration::new(Some("cancel-reuse".to_string()));
+        let new = ComparisonRegistration::new(Some("cancel-reuse".to_string()));
+        drop(old);
+        assert_eq!(cancel_comparison("cancel-reuse"), Ok(true));
+        assert!(new.token.is_cancelled());
+        drop(new);
+        assert_eq!(cancel_comparison("cancel-reuse"), Ok(false));
+    }
+
+    #[test]
+    fn test_ignore_rules_gitignore_semantics() {
+        use std::fs;
+
+        let root = std::env::temp_dir().join("diffvibe_ignore_test");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("src/gen")).unwrap();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        for file in ["a.log", "keep.log", "src/b.log", "src/main.rs", "src/x1.rs", "src/gen/out.rs", "docs/build", "build"] {
+            fs::write(root.join(file), "x").unwrap();
+        }
+        fs::write(root.join("src/.gitignore"), "gen/\nx?.rs\n").unwrap();
+        fs::write(root.join("src/.ignore"), "!b.log\n").unwrap();
+
+        let patterns = vec!["*.log".to_string(), "!keep.log".to_string(), "/build".to_string()];
+        let rules = IgnoreRules::new(&root, &patterns, HiddenFiles::Exclude).unwrap();
+        let tree = build_dir_tree(&root, &root, &rules, &WalkState::default(), &CancelToken::default()).unwrap();
+
+        fn collect(entries: &[DirEntry], out: &mut Vec<String>) {
+            for entry in entries {
+                out.push(entry.rel_path.clone());
+                collect(&entry.children, out);
+            }
+        }
+        let mut paths = Vec::new();
+        collect(&tree, &mut paths);
+        paths.sort();
+        assert_eq!(paths, vec!["docs", "docs/build", "keep.log", "src", "src/b.log", "src/main.rs"]);
+
+        // Expanding a subdirectory picks up ignore files above it
+        let nested = IgnoreRules::for_dir(&root, &patterns, HiddenFiles::Exclude, &root.join("src/gen")).unwrap();
+        assert!(nested.is_ignored(&root.join("src/gen/x2.rs"), false));
 
-#[tauri::command]
-async fn scan_directory(path: &str, ignore_patterns: Vec<String>) -> Result<ScanResult, String> {
-    let root = Path::new(path);
-    if !root.is_dir() {
-        return Err(format!("{} is not a directory", path));
+        let _ = fs::remove_dir_all(&root);
     }
 
-    let path_owned = path.to_string();
-    let ignore_patterns = ignore_patterns.clone();
+    #[test]
+    fn test_hidden_files_modes() {
+        use std::fs;
 
-    // Run in blocking thread pool to avoid blocking async runtime
-    let result = tokio::task::spawn_blocking(move || {
-        let root = Path::new(&path_owned);
-        let entries = build_dir_tree(root, root, &ignore_patterns)?;
-        let file_count = count_files(&entries);
+        let root = std::env::temp_dir().join("diffvibe_hidden_test");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::create_dir_all(root.join(".github")).unwrap();
+        fs::write(root.join(".git/HEAD"), "ref").unwrap();
+        fs::write(root.join(".github/ci.yml"), "ci").unwrap();
+        fs::write(root.join(".env.example"), "A=1").unwrap();
+        fs::write(root.join("main.rs"), "fn main() {}").unwrap();
+
+        let scan = |hidden| {
+            let tree_with = |patterns: &[String], max_depth| {
+                let options = ScanOptions { hidden_files: hidden, max_depth, ..Default::default() };
+                let rules = IgnoreRules::new(&root, patterns, hidden).unwrap();
+                build_dir_tree(&root, &root, &rules, &WalkState::new(&options, &root), &CancelToken::default()).unwrap()
+            };
+            let aligned = merge_directory_trees(&tree_with(&[], None), &[]);
+            let mut paths = Vec::new();
+            collect_file_paths(&aligned, &mut paths);
+            paths.sort();
+
+            let patterns = [".git/".to_string(), ".env*".to_string()];
+            let top = |entries: &[DirEntry]| entries.iter().map(|e| e.name.clone()).collect::<Vec<_>>();
+            (paths, top(&tree_with(&patterns, None)), top(&tree_with(&patterns, Some(1))))
+        };
 
-        Ok::<ScanResult, String>(ScanResult {
-            root_path: path_owned,
-            entries,
-            file_count,
-        })
-    })
-    .await
-    .map_err(|e| format!("Scan task failed: {}", e))??;
+        let (paths, tree, lazy) = scan(HiddenFiles::Exclude);
+        assert_eq!(paths, vec!["main.rs"]);
+        assert_eq!(tree, vec!["main.rs"]);
+        assert_eq!(lazy, tree);
 
-    Ok(result)
-}
+        // Ignored dotfiles are shown, but the .git/ pattern still drops .git
+        let (paths, tree, lazy) = scan(HiddenFiles::Include);
+        assert_eq!(paths, vec![".env.example", ".git/HEAD", ".github/ci.yml", "main.rs"]);
+        assert_eq!(tree, vec![".github", ".env.example", "main.rs"]);
+        assert_eq!(lazy, tree);
 
-/// Compare two directories and return aligned tree with progress events
-#[tauri::command]
-async fn compare_directories_async(
-    window: tauri::Window,
-    left_path: String,
-    right_path: String,
-    ignore_patterns: Vec<String>,
-) -> Result<AlignedScanResult, String> {
-    let left_root = Path::new(&left_path);
-    let right_root = Path::new(&right_path);
+        // Ignore rules decide for every hidden entry
+        let (_, tree, lazy) = scan(HiddenFiles::IgnoreRules);
+        assert_eq!(tree, vec![".github", "main.rs"]);
+        assert_eq!(lazy, tree);
 
-    if !left_root.is_dir() {
-        return Err(format!("{} is not a directory", left_path));
-    }
-    if !right_root.is_dir() {
-        return Err(format!("{} is not a directory", right_path));
+        let _ = fs::remove_dir_all(&root);
     }
 
-    // Run in blocking thread pool
-    let result = tokio::task::spawn_blocking(move || {
-        let left_root = Path::new(&left_path);
-        let right_root = Path::new(&right_path);
-
-        // Phase 1: Scan left directory
-        let _ = window.emit("directory-scan-progress", ScanProgress {
-            phase: "scanning-left".to_string(),
-            files: 0,
-            message: format!("Scanning {}...", left_path),
-        });
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks_and_empty_dirs() {
+        use std::fs;
+        use std::os::unix::fs::symlink;
 
-        let left_entries = build_dir_tree(left_root, left_root, &ignore_patterns)?;
-        let left_count = count_files(&left_entries);
+        let temp = std::env::temp_dir().join("diffvibe_symlink_test");
+        let left = temp.join("left");
+        let right = temp.join("right");
+        let _ = fs::remove_dir_all(&temp);
+        for side in [&left, &right] {
+            fs::create_dir_all(side.join("empty")).unwrap();
+            fs::create_dir_all(side.join("sub")).unwrap();
+            fs::write(side.join("sub/file.txt"), "content").unwrap();
+            // Points back up the tree: only safe because loops are detected
+            symlink("..", side.join("sub/loop")).unwrap();
+        }
+        symlink("sub/file.txt", left.join("link")).unwrap();
+        symlink("empty", right.join("link")).unwrap();
 
-        let _ = window.emit("directory-scan-progress", ScanProgress {
-            phase: "scanning-left".to_string(),
-            files: left_count,
-            message: format!("Scanned {} ({} files)", left_path, left_count),
-        });
+        let scan = |root: &Path, follow: bool| {
+            let rules = IgnoreRules::new(root, &[], HiddenFiles::Exclude).unwrap();
+            let options = ScanOptions { follow_symlinks: follow, ..Default::default() };
+            build_dir_tree(root, root, &rules, &WalkState::new(&options, root), &CancelToken::default()).unwrap()
+        };
+        let left_tree = scan(&left, false);
+        let right_tree = scan(&right, false);
+
+        let empty = left_tree.iter().find(|e| e.name == "empty").unwrap();
+        assert_eq!((empty.kind, empty.children.len()), (EntryKind::Dir, 0));
+        let link = left_tree.iter().find(|e| e.name == "link").unwrap();
+        assert_eq!(link.kind, EntryKind::Symlink);
+        assert_eq!(link.link_target.as_deref(), Some("sub/file.txt"));
+
+        // Links compare by target when not followed
+        let aligned = merge_directory_trees(&left_tree, &right_tree);
+        let link = aligned.iter().find(|e| e.name == "link").unwrap();
+        assert!(matches!(link.status, EntryStatus::Modified));
+        let sub = aligned.iter().find(|e| e.name == "sub").unwrap();
+        assert!(matches!(sub.status, EntryStatus::Match));
+
+        // Following links terminates and reports the loop as a link
+        let followed = scan(&left, true);
+        let link = followed.iter().find(|e| e.name == "link").unwrap();
+        assert_eq!((link.kind, link.size), (EntryKind::File, 7));
+        let sub = followed.iter().find(|e| e.name == "sub").unwrap();
+        let looped = sub.children.iter().find(|e| e.name == "loop").unwrap();
+        assert_eq!(looped.kind, EntryKind::Symlink);
 
-        // Phase 2: Scan right directory
-        let _ = window.emit("directory-scan-progress", ScanProgress {
-            phase: "scanning-right".to_string(),
-            files: left_count,
-            message: format!("Scanning {}...", right_path),
-        });
+        let _ = fs::remove_dir_all(&temp);
+    }
 
-        let right_entries = build_dir_tree(right_root, right_root, &ignore_patterns)?;
-        let right_count = count_files(&right_entries);
+    #[cfg(unix)]
+    #[test]
+    fn test_metadata_only_status() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
 
-        let _ = window.emit("directory-scan-progress", ScanProgress {
-            phase: "scanning-right".to_string(),
-            files: left_count + right_count,
-            message: format!("Scanned {} ({} files)", right_path, right_count),
-        });
+        let temp = std::env::temp_dir().join("diffvibe_metadata_test");
+        let left = temp.join("left");
+        let right = temp.join("right");
+        let _ = fs::remove_dir_all(&temp);
+        for side in [&left, &right] {
+            fs::create_dir_all(side.join("bin")).unwrap();
+            fs::write(side.join("bin/run.sh"), "echo hi").unwrap();
+            fs::write(side.join("bin/other.sh"), "echo hi").unwrap();
+        }
+        fs::set_permissions(right.join("bin/run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::set_permissions(left.join("bin/run.sh"), fs::Permissions::from_mode(0o644)).unwrap();
+        // Same mtimes everywhere so only the mode differs
+        let mtime = SystemTime::now() - Duration::from_secs(3600);
+        for file in ["left/bin/run.sh", "right/bin/run.sh", "left/bin/other.sh", "right/bin/other.sh"] {
+            fs::File::options().write(true).open(temp.join(file)).unwrap().set_modified(mtime).unwrap();
+        }
 
-        // Phase 3: Merge trees
-        let _ = window.emit("directory-scan-progress", ScanProgress {
-            phase: "merging".to_string(),
-            files: left_count + right_count,
-            message: "Computing differences...".to_string(),
-        });
+        let scan = |root: &Path, compare_metadata: bool| {
+            let options = ScanOptions { compare_metadata, ..Default::default() };
+            let rules = IgnoreRules::new(root, &[], options.hidden_files).unwrap();
+            build_dir_tree(root, root, &rules, &WalkState::new(&options, root), &CancelToken::default()).unwrap()
+        };
 
-        let aligned_entries = merge_directory_trees(&left_entries, &right_entries);
-        let stats = count_aligned_stats(&aligned_entries);
+        let aligned = merge_directory_trees(&scan(&left, false), &scan(&right, false));
+        assert!(matches!(aligned[0].status, EntryStatus::Match));
 
-        let _ = window.emit("directory-scan-progress", ScanProgress {
-            phase: "complete".to_string(),
-            files: stats.total_files,
-            message: format!("Complete: {} files compared", stats.total_files),
-        });
+        let mut aligned = merge_directory_trees(&scan(&left, true), &scan(&right, true));
+        refine_content_status(&mut aligned, &left, &right, CompareMethod::Hash, &CancelToken::default(), |_, _| {}).unwrap();
+        let bin = &aligned[0];
+        assert!(matches!(bin.status, EntryStatus::MetadataOnly));
+        let run = bin.children.iter().find(|e| e.name == "run.sh").unwrap();
+        assert!(matches!(run.status, EntryStatus::MetadataOnly));
+        assert_eq!(run.right_metadata.as_ref().unwrap().mode, Some(0o755));
+        let stats = count_aligned_stats(&aligned);
+        assert_eq!((stats.identical, stats.metadata_only), (1, 1));
 
-        Ok::<AlignedScanResult, String>(AlignedScanResult {
-            root_left: left_path,
-            root_right: right_path,
-            entries: aligned_entries,
-            stats,
-        })
-    })
-    .await
-    .map_err(|e| format!("Compare task failed: {}", e))??;
+        let _ = fs::remove_dir_all(&temp);
+    }
 
-    Ok(result)
-}
+    fn collect_file_paths(entries: &[AlignedEntry], out: &mut Vec<String>) {
+        for entry in entries {
+            if entry.is_dir {
+                collect_file_paths(&entry.children, out);
+            } else {
+                out.push(entry.rel_path.clone());
+            }
+        }
+    }
 
-#[cfg_attr(mobile, tauri::mobile_entry_point)]
-pub fn run() {
-    // Initialize tracing with environment-based filtering
-    // Set RUST_LOG=debug for verbose logging, RUST_LOG=info for normal
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"))
-        )
-        .with_target(false)
-        .with_thread_ids(false)
-        .with_file(false)
-        .with_line_number(false)
-        .compact()
-        .init();
-    
-    info!("DiffVibe starting...");
-    
-    // Parse CLI args before starting Tauri
-    parse_cli_args();
+    #[test]
+    fn test_compare_options_depth_and_ignores() {
+        use std::fs;
 
-    tauri::Builder::default()
-        .plugin(tauri_plugin_dialog::init())
-        .plugin(tauri_plugin_fs::init())
-        .plugin(tauri_plugin_opener::init())
-        .plugin(tauri_plugin_store::Builder::default().build())
-        .invoke_handler(tauri::generate_handler![read_file, write_file, copy_file, copy_dir, file_exists, is_directory, compute_diff, compute_diff_files, compute_three_way_diff, compute_image_diff_mask, get_cli_args, exit_app, compare_directories, scan_directory, scan_directory_lazy, expand_directory, get_diff_stats, compare_directories_async])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
-}
+        let temp = std::env::temp_dir().join("diffvibe_compare_options_test");
+        let left = temp.join("left");
+        let right = temp.join("right");
+        let _ = fs::remove_dir_all(&temp);
+        for side in [&left, &right] {
+            fs::create_dir_all(side.join("a/b")).unwrap();
+            fs::write(side.join("top.txt"), "top").unwrap();
+        }
+        fs::write(left.join("a/b/deep.txt"), "left").unwrap();
+        fs::write(right.join("a/b/deep.txt"), "right").unwrap();
+        fs::write(left.join("debug.log"), "log").unwrap();
+
+        let (l, r) = (left.to_str().unwrap(), right.to_str().unwrap());
+        let full = compare_directories(l, r, None).unwrap();
+        assert_eq!((full.stats.modified, full.stats.left_only), (1, 1));
+
+        // Both entry points share the engine and options
+        let options = CompareOptions {
+            ignore_patterns: vec!["*.log".to_string()],
+            scan: ScanOptions { max_depth: Some(2), ..Default::default() },
+            ..Default::default()
+        };
+        let limited = compare_directory_trees(l, r, &options, &CancelToken::default(), |_| {}, |_, _| {}).unwrap();
+        assert_eq!((limited.stats.identical, limited.stats.total_files), (1, 1));
+        let b = &limited.entries[0].children[0];
+        assert_eq!((b.name.as_str(), b.children.len()), ("b", 0));
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let _ = fs::remove_dir_all(&temp);
+    }
 
     #[test]
-    fn test_compare_directories() {
+    fn test_compare_streams_batches() {
         use std::fs;
 
-        // Create temp test directories
-        let temp = std::env::temp_dir().join("diffvibe_test");
+        let temp = std::env::temp_dir().join("diffvibe_stream_test");
         let left = temp.join("left");
         let right = temp.join("right");
-
-        // Clean up any previous test
         let _ = fs::remove_dir_all(&temp);
-        fs::create_dir_all(&left).unwrap();
-        fs::create_dir_all(&right).unwrap();
+        for side in [&left, &right] {
+            fs::create_dir_all(side.join("alpha/nested")).unwrap();
+            fs::create_dir_all(side.join("beta")).unwrap();
+            fs::write(side.join("alpha/nested/a.txt"), "a").unwrap();
+            fs::write(side.join("top.txt"), "top").unwrap();
+        }
+        fs::write(left.join("beta/b.txt"), "left").unwrap();
+        fs::write(right.join("beta/b.txt"), "rite").unwrap();
+        fs::write(right.join("zeta.txt"), "new").unwrap();
 
-        // Same file
-        fs::write(left.join("same.txt"), "same content").unwrap();
-        fs::write(right.join("same.txt"), "same content").unwrap();
+        let batches: Mutex<Vec<(String, Vec<String>)>> = Mutex::new(Vec::new());
+        let result = compare_directory_trees(
+            left.to_str().unwrap(),
+            right.to_str().unwrap(),
+            &CompareOptions::default(),
+            &CancelToken::default(),
+            |_| {},
+            |parent, batch| {
+                let names = batch.iter().map(|e| e.name.clone()).collect();
+                batches.lock().unwrap().push((parent.to_string(), names));
+            },
+        ).unwrap();
 
-        // Modified file
-        fs::write(left.join("modified.txt"), "left version").unwrap();
-        fs::write(right.join("modified.txt"), "right version").unwrap();
+        // Few top-level directories, so they're listed deeper; listed ones come first
+        let mut batches = batches.into_inner().unwrap();
+        let batch = |parent: &str, names: &[&str]| (parent.to_string(), names.iter().map(|n| n.to_string()).collect::<Vec<_>>());
+        assert_eq!(batches[..2], [batch("", &["alpha", "beta"]), batch("alpha", &["nested"])]);
+        batches[2..].sort();
+        assert_eq!(batches[2..], [batch("", &["top.txt", "zeta.txt"]), batch("alpha/nested", &["a.txt"]), batch("beta", &["b.txt"])]);
+        let names: Vec<&str> = result.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["alpha", "beta", "top.txt", "zeta.txt"]);
+        assert!(matches!(result.entries[1].status, EntryStatus::Modified));
+        assert_eq!(result.entries[0].children[0].children.len(), 1);
+        assert_eq!((result.stats.identical, result.stats.modified, result.stats.right_only), (2, 1, 1));
 
-        // Left only
-        fs::write(left.join("left-only.txt"), "only left").unwrap();
+        let _ = fs::remove_dir_all(&temp);
+    }
 
-        // Right only
-        fs::write(right.join("right-only.txt"), "only right").unwrap();
+    #[test]
+    fn test_compare_one_sided_dirs_keep_status() {
+        use std::fs;
 
-        let result = compare_directories(
+        let temp = std::env::temp_dir().join("diffvibe_one_sided_test");
+        let left = temp.join("left");
+        let right = temp.join("right");
+        let _ = fs::remove_dir_all(&temp);
+        // Deep enough that `deep/only` is scanned whole rather than listed
+        let deep = Path::new("d1/d2/d3/d4/d5");
+        for side in [&left, &right] {
+            fs::create_dir_all(side.join(deep)).unwrap();
+            fs::write(side.join(deep).join("same.txt"), "same").unwrap();
+        }
+        fs::create_dir_all(left.join(deep).join("only")).unwrap();
+        fs::write(left.join(deep).join("only/f.txt"), "f").unwrap();
+        fs::create_dir_all(left.join("shallow")).unwrap();
+        fs::write(left.join("shallow/g.txt"), "g").unwrap();
+
+        let result = compare_directory_trees(
             left.to_str().unwrap(),
-            right.to_str().unwrap()
+            right.to_str().unwrap(),
+            &CompareOptions::default(),
+            &CancelToken::default(),
+            |_| {},
+            |_, _| {},
         ).unwrap();
 
-        assert_eq!(result.stats.identical, 1);
-        assert_eq!(result.stats.modified, 1);
-        assert_eq!(result.stats.left_only, 1);
-        assert_eq!(result.stats.right_only, 1);
-        assert_eq!(result.stats.total_files, 4);
+        fn find<'a>(entries: &'a [AlignedEntry], rel_path: &str) -> &'a AlignedEntry {
+            let entry = entries.iter().find(|e| rel_path == e.rel_path || rel_path.starts_with(&format!("{}/", e.rel_path))).unwrap();
+            if entry.rel_path == rel_path { entry } else { find(&entry.children, rel_path) }
+        }
+        // A one-sided directory stays left-only at any depth; its parents are modified
+        assert!(matches!(find(&result.entries, "shallow").status, EntryStatus::LeftOnly));
+        assert!(matches!(find(&result.entries, "d1/d2/d3/d4/d5/only").status, EntryStatus::LeftOnly));
+        assert!(matches!(find(&result.entries, "d1/d2/d3/d4/d5").status, EntryStatus::Modified));
+        assert!(matches!(find(&result.entries, "d1").status, EntryStatus::Modified));
 
-        // Clean up
         let _ = fs::remove_dir_all(&temp);
     }
 
     #[test]
-    fn test_three_way_all_same() {
-        let base = "line 1\nline 2";
-        let local = "line 1\nline 2";
-        let remote = "line 1\nline 2";
+    fn test_compare_unreadable_subdirectory() {
+        use std::fs;
+
+        let temp = std::env::temp_dir().join("diffvibe_unreadable_test");
+        let left = temp.join("left");
+        let right = temp.join("right");
+        let _ = fs::remove_dir_all(&temp);
+        for side in [&left, &right] {
+            fs::create_dir_all(side.join("locked")).unwrap();
+            fs::write(side.join("locked/a.txt"), "a").unwrap();
+            fs::write(side.join("top.txt"), "top").unwrap();
+        }
 
-        let result = compute_three_way_diff(base, local, remote);
+        // A subdirectory gone by the time it's listed counts as empty; a root doesn't
+        let side = CompareSide::new(&left, &CompareOptions::default()).unwrap();
+        let vanished = CompareSide { dir: left.join("vanished"), ..side };
+        assert!(vanished.list(&CancelToken::default()).unwrap().is_empty());
+        let missing_root = temp.join("missing");
+        let root = CompareSide::new(&missing_root, &CompareOptions::default()).unwrap();
+        assert!(root.list(&CancelToken::default()).is_err());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let locked = left.join("locked");
+            fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+            // Permissions don't stop root
+            if fs::read_dir(&locked).is_err() {
+                let result = compare_directory_trees(
+                    left.to_str().unwrap(),
+                    right.to_str().unwrap(),
+                    &CompareOptions::default(),
+                    &CancelToken::default(),
+                    |_| {},
+                    |_, _| {},
+                ).unwrap();
+                let locked_entry = result.entries.iter().find(|e| e.name == "locked").unwrap();
+                assert_eq!(locked_entry.children.len(), 1, "right side's file still listed");
+                assert_eq!(result.stats.identical, 1);
+            }
+            fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        }
 
-        assert_eq!(result.conflict_count, 0);
-        assert_eq!(result.merged_content, "line 1\nline 2");
+        let _ = fs::remove_dir_all(&temp);
     }
 
     #[test]
-    fn test_three_way_with_conflict() {
-        let base = "original line";
-        let local = "local change";
-        let remote = "remote change";
+    fn test_merge_directories_states() {
+        use std::fs;
 
-        let result = compute_three_way_diff(base, local, remote);
+        let temp = std::env::temp_dir().join("diffvibe_dirmerge_test");
+        let [local, base, remote] = ["local", "base", "remote"].map(|side| temp.join(side));
+        let _ = fs::remove_dir_all(&temp);
+        for side in [&local, &base, &remote] {
+            fs::create_dir_all(side.join("docs")).unwrap();
+            fs::write(side.join("same.txt"), "same").unwrap();
+            fs::write(side.join("docs/guide.txt"), "guide").unwrap();
+        }
+        let write = |side: &Path, name: &str, content: &str| fs::write(side.join(name), content).unwrap();
+
+        write(&base, "ours.txt", "base");
+        write(&local, "ours.txt", "local edit");
+        write(&remote, "ours.txt", "base");
+        write(&base, "theirs.txt", "base");
+        write(&local, "theirs.txt", "base");
+        write(&remote, "theirs.txt", "remote edit");
+        // Edits on different lines merge cleanly; edits to the same line conflict
+        write(&base, "clean.txt", "one\ntwo\nthree\n");
+        write(&local, "clean.txt", "ONE\ntwo\nthree\n");
+        write(&remote, "clean.txt", "one\ntwo\nTHREE\n");
+        write(&base, "clash.txt", "one\ntwo\n");
+        write(&local, "clash.txt", "one\nlocal\n");
+        write(&remote, "clash.txt", "one\nremote\n");
+        write(&local, "new-same.txt", "new");
+        write(&remote, "new-same.txt", "new");
+        write(&local, "new-diff.txt", "local");
+        write(&remote, "new-diff.txt", "remote");
+        write(&local, "local-new.txt", "new");
+        write(&base, "gone.txt", "old");
+        write(&remote, "gone.txt", "old");
+        write(&base, "edit-gone.txt", "old");
+        write(&local, "edit-gone.txt", "edited");
+        write(&remote, "docs/guide.txt", "guide v2");
+
+        let result = merge_three_directories(
+            local.to_str().unwrap(),
+            base.to_str().unwrap(),
+            remote.to_str().unwrap(),
+            &CompareOptions::default(),
+            &MergeOptions::default(),
+        ).unwrap();
+        let find = |name: &str| result.entries.iter().find(|e| e.name == name).unwrap();
+
+        assert_eq!(find("same.txt").state, MergeState::Unchanged);
+        assert_eq!(find("ours.txt").state, MergeState::ChangedLocal);
+        assert_eq!(find("theirs.txt").state, MergeState::ChangedRemote);
+        assert_eq!(find("local-new.txt").state, MergeState::AddedLocal);
+        assert_eq!(find("new-same.txt").state, MergeState::AddedBoth);
+        assert_eq!(find("new-diff.txt").state, MergeState::AddAddConflict);
+        assert_eq!(find("gone.txt").state, MergeState::DeletedLocal);
+        assert_eq!(find("edit-gone.txt").state, MergeState::ModifyDeleteConflict);
+
+        let clean = find("clean.txt");
+        assert_eq!((clean.state, clean.conflict_count, clean.has_conflict), (MergeState::ChangedBoth, Some(0), false));
+        let clash = find("clash.txt");
+        assert_eq!((clash.state, clash.conflict_count, clash.has_conflict), (MergeState::ChangedBoth, Some(1), true));
+
+        let docs = find("docs");
+        assert_eq!((docs.state, docs.has_conflict), (MergeState::ChangedRemote, false));
+        assert_eq!(docs.children[0].state, MergeState::ChangedRemote);
+
+        assert_eq!(result.stats.total_files, 11);
+        assert_eq!(result.stats.conflicts, 3);
+        assert_eq!(result.stats.unchanged, 1);
 
-        assert_eq!(result.conflict_count, 1);
-        assert!(result.merged_content.contains("<<<<<<< LOCAL"));
-        assert!(result.merged_content.contains("======="));
-        assert!(result.merged_content.contains(">>>>>>> REMOTE"));
+        let _ = fs::remove_dir_all(&temp);
     }
 
     #[test]
-    fn test_three_way_same_change() {
-        let base = "original";
-        let local = "changed";
-        let remote = "changed";
+    fn test_sync_plan_and_execute() {
+        use std::fs;
 
-        let result = compute_three_way_diff(base, local, remote);
+        let temp = std::env::temp_dir().join("diffvibe_sync_test");
+        let left = temp.join("left");
+        let right = temp.join("right");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(left.join("release/bin")).unwrap();
+        fs::create_dir_all(&right).unwrap();
+        fs::write(left.join("release/bin/app"), "v2").unwrap();
+        fs::write(left.join("release/notes.txt"), "notes").unwrap();
+        fs::write(left.join("changed.txt"), "left").unwrap();
+        fs::write(right.join("changed.txt"), "right").unwrap();
+        fs::write(right.join("stale.txt"), "old").unwrap();
+        // Only on the right: kept by the merging copy, except a directory
+        // where the left has a file, which the plan deletes explicitly
+        fs::create_dir_all(right.join("release/notes.txt")).unwrap();
+        fs::write(right.join("release/keep.txt"), "keep").unwrap();
+
+        let item = |rel_path: &str, action| SyncItem { rel_path: rel_path.to_string(), action };
+        let items = vec![
+            item("release", SyncAction::CopyToRight),
+            // Covered by the directory copy
+            item("release/notes.txt", SyncAction::CopyToRight),
+            item("changed.txt", SyncAction::CopyToRight),
+            item("stale.txt", SyncAction::DeleteRight),
+        ];
+        let (l, r) = (left.to_str().unwrap(), right.to_str().unwrap());
+
+        let plan = sync_directory_entries(l, r, &items, true, |_| {}).unwrap();
+        let planned: Vec<(&str, bool)> = plan.operations.iter().map(|op| (op.rel_path.as_str(), op.replaces)).collect();
+        assert_eq!(
+            planned,
+            vec![("changed.txt", true), ("release/notes.txt", false), ("release", true), ("stale.txt", false)]
+        );
+        assert_eq!(plan.operations[1].action, SyncAction::DeleteRight);
+        assert_eq!(plan.operations[2].bytes, 7);
+        assert!(plan.results.is_empty());
+        assert!(!right.join("release/bin").exists(), "dry run must not touch the disk");
+
+        let progress = std::cell::RefCell::new(Vec::new());
+        let report = sync_directory_entries(l, r, &items, false, |p| progress.borrow_mut().push(p.done)).unwrap();
+        assert!(report.results.iter().all(|r| r.error.is_none()));
+        assert_eq!(*progress.borrow(), vec![0, 1, 2, 3, 4]);
+        assert_eq!(fs::read_to_string(right.join("release/bin/app")).unwrap(), "v2");
+        assert_eq!(fs::read_to_string(right.join("release/notes.txt")).unwrap(), "notes");
+        assert_eq!(fs::read_to_string(right.join("release/keep.txt")).unwrap(), "keep");
+        assert_eq!(fs::read_to_string(right.join("changed.txt")).unwrap(), "left");
+        assert!(!right.join("stale.txt").exists());
+        assert!(!right.join(".changed.txt.diffvibe-old").exists());
+
+        // Paths must stay inside the roots, and sources must exist
+        assert!(sync_directory_entries(l, r, &[item("../left", SyncAction::DeleteRight)], true, |_| {}).is_err());
+        assert!(sync_directory_entries(l, r, &[item("missing.txt", SyncAction::CopyToLeft)], true, |_| {}).is_err());
+        // An entry inside a selected directory can't ask for a different action
+        let conflicting = [item("release", SyncAction::CopyToRight), item("release/bin", SyncAction::CopyToLeft)];
+        assert!(sync_directory_entries(l, r, &conflicting, true, |_| {}).is_err());
 
-        assert_eq!(result.conflict_count, 0);
-        assert_eq!(result.merged_content, "changed");
+        let _ = fs::remove_dir_all(&temp);
     }
 
     #[test]
-    fn test_three_way_local_only() {
-        let base = "line 1\nline 2";
-        let local = "line 1\nmodified";  // Local changed line 2
-        let remote = "line 1\nline 2";   // Remote unchanged
+    fn test_delete_entries_and_undo() {
+        use std::fs;
 
-        let result = compute_three_way_diff(base, local, remote);
+        let temp = std::env::temp_dir().join("diffvibe_trash_test");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(temp.join("build/out")).unwrap();
+        fs::write(temp.join("build/out/app.o"), "obj").unwrap();
+        fs::write(temp.join("notes.txt"), "notes").unwrap();
+        let root = temp.to_str().unwrap();
+
+        let rel_paths = ["build", "build/out/app.o", "notes.txt"].map(String::from);
+        let batch = trash_entries(root, &rel_paths).unwrap();
+        let trashed: Vec<(&str, bool)> = batch.entries.iter().map(|e| (e.rel_path.as_str(), e.is_dir)).collect();
+        assert_eq!(trashed, vec![("build", true), ("notes.txt", false)]);
+        assert!(!temp.join("build").exists());
+        assert!(!temp.join("notes.txt").exists());
+
+        // Restoring refuses to overwrite anything created since
+        fs::write(temp.join("notes.txt"), "new notes").unwrap();
+        assert!(restore_trash_batch(&batch.id).is_err());
+        assert!(!temp.join("build").exists());
+        fs::remove_file(temp.join("notes.txt")).unwrap();
+
+        // Entries already back in place (e.g. after an interrupted restore) are skipped
+        fs::rename(&batch.entries[1].trashed_path, temp.join("notes.txt")).unwrap();
+        let restored = restore_trash_batch(&batch.id).unwrap();
+        assert_eq!(restored.entries.len(), 1);
+        assert_eq!(fs::read_to_string(temp.join("build/out/app.o")).unwrap(), "obj");
+        assert_eq!(fs::read_to_string(temp.join("notes.txt")).unwrap(), "notes");
+        assert!(restore_trash_batch(&batch.id).is_err());
+
+        // A partly removed source is made whole from its complete copy
+        let copy = temp.join("copy");
+        copy_tree(&temp.join("build"), &copy).unwrap();
+        fs::remove_file(temp.join("build/out/app.o")).unwrap();
+        fill_missing(&copy, &temp.join("build")).unwrap();
+        assert_eq!(fs::read_to_string(temp.join("build/out/app.o")).unwrap(), "obj");
+
+        // Nothing is moved when any entry is missing
+        let missing = ["notes.txt", "gone.txt"].map(String::from);
+        assert!(trash_entries(root, &missing).is_err());
+        assert!(temp.join("notes.txt").exists());
+        assert!(restore_trash_batch("../etc").is_err());
 
-        assert_eq!(result.conflict_count, 0);
-        assert!(result.merged_content.contains("modified"));
-        assert!(!result.merged_content.contains("line 2")); // Base line should be replaced
+        let _ = fs::remove_dir_all(&temp);
     }
 
     #[test]
-    fn test_three_way_remote_only() {
-        let base = "line 1\nline 2";
-        let local = "line 1\nline 2";    // Local unchanged
-        let remote = "line 1\nchanged";  // Remote changed line 2
+    fn test_write_file_atomic_and_backup() {
+        use std::fs;
 
-        let result = compute_three_way_diff(base, local, remote);
+        let temp = std::env::temp_dir().join("diffvibe_write_test");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(&temp).unwrap();
+        let file = temp.join("notes.txt");
+        let path = file.to_str().unwrap();
+
+        write_file(path, "first", "utf-8", None, None).unwrap();
+        write_file(path, "second", "utf-8", Some(false), None).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "second");
+        assert!(!temp.join("notes.txt.bak").exists(), "backups can be turned off");
+
+        // Backups stay on when the caller doesn't say
+        write_file(path, "third", "utf-8", None, None).unwrap();
+        assert_eq!(fs::read_to_string(temp.join("notes.txt.bak")).unwrap(), "second");
+        // Concurrent saves of one file each use their own temp file
+        std::thread::scope(|scope| {
+            for k in 0..8 {
+                let file = &file;
+                scope.spawn(move || write_atomic(file, format!("save {}", k).as_bytes()).unwrap());
+            }
+        });
+        assert!(fs::read_to_string(&file).unwrap().starts_with("save "));
+        let names: Vec<String> = fs::read_dir(&temp).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect();
+        assert_eq!(names.len(), 2, "no temp files left behind: {:?}", names);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&file, fs::Permissions::from_mode(0o4750)).unwrap();
+            let link = temp.join("link.txt");
+            std::os::unix::fs::symlink(&file, &link).unwrap();
+
+            write_file(link.to_str().unwrap(), "via link", "utf-8", None, None).unwrap();
+            assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+            assert_eq!(fs::read_to_string(&file).unwrap(), "via link");
+            assert_eq!(fs::metadata(&file).unwrap().permissions().mode() & 0o7777, 0o4750);
+        }
 
-        assert_eq!(result.conflict_count, 0);
-        assert!(result.merged_content.contains("changed"));
-        assert!(!result.merged_content.contains("line 2")); // Base line should be replaced
+        let _ = fs::remove_dir_all(&temp);
     }
 
     #[test]
-    fn test_three_way_realistic() {
-        // Simulate a more realistic scenario
-        let base = "// Header
-function foo() {
-    return 1;
-}
-
-function bar() {
-    return 2;
-}";
-
-        // Local adds logging to foo
-        let local = "// Header
-function foo() {
-    console.log('foo called');
-    return 1;
-}
-
-function bar() {
-    return 2;
-}";
-
-        // Remote changes bar's return value
-        let remote = "// Header
-function foo() {
-    return 1;
-}
-
-function bar() {
-    return 42;
-}";
-
-        let result = compute_three_way_diff(base, local, remote);
+    fn test_write_file_detects_external_changes() {
+        use std::fs;
 
-        println!("=== MERGED OUTPUT ===");
-        println!("{}", result.merged_content);
-        println!("=== CONFLICTS: {} ===", result.conflict_count);
+        let temp = std::env::temp_dir().join("diffvibe_version_test");
+        let _ = fs::remove_dir_all(&temp);
+        fs::create_dir_all(&temp).unwrap();
+        let file = temp.join("doc.txt");
+        let path = file.to_str().unwrap();
+        fs::write(&file, "original").unwrap();
+
+        let opened = read_file(path).unwrap().version.unwrap();
+        let saved = write_file(path, "ours", "utf-8", None, Some(opened.clone())).unwrap();
+        assert_eq!(read_file(path).unwrap().version.as_ref(), Some(&saved));
+
+        // A save based on the stale version is refused and leaves the file alone
+        fs::write(&file, "another editor").unwrap();
+        let err = write_file(path, "ours again", "utf-8", None, Some(saved.clone())).unwrap_err();
+        let actual = current_version(&file);
+        assert_eq!(err, WriteError::ModifiedOnDisk { path: path.to_string(), expected: saved.clone(), actual });
+        assert_eq!(fs::read_to_string(&file).unwrap(), "another editor");
+
+        fs::remove_file(&file).unwrap();
+        let err = write_file(path, "ours again", "utf-8", None, Some(saved)).unwrap_err();
+        assert!(matches!(err, WriteError::ModifiedOnDisk { actual: None, .. }));
+        assert!(read_file(path).unwrap().version.is_none());
+
+        // Without a token the save goes through
+        write_file(path, "forced", "utf-8", None, None).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "forced");
 
-        // Should have both changes merged without conflict
-        assert_eq!(result.conflict_count, 0, "Should have no conflicts");
-        assert!(result.merged_content.contains("console.log"), "Should have local's logging");
-        assert!(result.merged_content.contains("return 42"), "Should have remote's return value");
+        let _ = fs::remove_dir_all(&temp);
     }
 }
@@ -2,11 +2,11 @@
  * Tab management store
  */
 import { recentStore } from './recent.svelte';
-import type { AlignedScanResult } from '$lib/types';
+import type { AlignedScanResult, DiffOptions, MergeOptions } from '$lib/types';
 
 export interface Tab {
   id: string;
-  type: 'home' | 'compare' | 'merge' | 'directory';
+  type: 'home' | 'compare' | 'merge' | 'directory' | 'dirmerge';
   title: string;
   // For compare/merge tabs
   leftPath?: string;
@@ -15,6 +15,10 @@ export interface Tab {
   mode?: 'file' | 'directory' | 'merge';
   // For merge mode - output file path
   outputPath?: string;
+  // Merge marker options from the command line (merge and dirmerge tabs)
+  mergeOptions?: MergeOptions;
+  // Line comparison options from the command line (compare tabs)
+  diffOptions?: DiffOptions;
   // Dirty state
   dirty?: boolean;
   // Parent tab ID (for back navigation from file diff to directory)
@@ -70,7 +74,7 @@ function createTabStore() {
       homeState = { ...homeState, ...state };
     },
 
-    openCompare(leftPath: string, rightPath: string, mode: 'file' | 'directory' | 'merge' = 'file', basePath?: string, parentTabId?: string) {
+    openCompare(leftPath: string, rightPath: string, mode: 'file' | 'directory' | 'merge' = 'file', basePath?: string, parentTabId?: string, diffOptions?: DiffOptions) {
       const leftName = getFileName(leftPath);
       const rightName = getFileName(rightPath);
       const title = `${leftName} ↔ ${rightName}`;
@@ -97,6 +101,7 @@ function createTabStore() {
         basePath,
         mode,
         parentTabId,
+        diffOptions,
       };
 
       tabs = [...tabs, newTab];
@@ -110,7 +115,7 @@ function createTabStore() {
       return id;
     },
 
-    openMerge(basePath: string, localPath: string, remotePath: string, outputPath?: string) {
+    openMerge(basePath: string, localPath: string, remotePath: string, outputPath?: string, mergeOptions?: MergeOptions) {
       const baseName = getFileName(basePath);
       const title = `Merge: ${baseName}`;
 
@@ -134,6 +139,7 @@ function createTabStore() {
         basePath,
         mode: 'merge',
         outputPath,
+        mergeOptions,
       };
 
       tabs = [...tabs, newTab];
@@ -145,6 +151,36 @@ function createTabStore() {
       return id;
     },
 
+    openDirectoryMerge(basePath: string, localPath: string, remotePath: string, mergeOptions?: MergeOptions) {
+      const title = `Merge: ${getFileName(localPath)} ↔ ${getFileName(remotePath)}`;
+
+      const existing = tabs.find(
+        (t) => t.type === 'dirmerge' && t.basePath === basePath && t.leftPath === localPath && t.rightPath === remotePath
+      );
+
+      if (existing) {
+        activeTabId = existing.id;
+        return existing.id;
+      }
+
+      const id = generateId();
+      const newTab: Tab = {
+        id,
+        type: 'dirmerge',
+        title,
+        leftPath: localPath,
+        rightPath: remotePath,
+        basePath,
+        mode: 'merge',
+        mergeOptions,
+      };
+
+      tabs = [...tabs, newTab];
+      activeTabId = id;
+
+      return id;
+    },
+
     setActive(id: string) {
       if (tabs.some((t) => t.id === id)) {
         activeTabId = id;
@@ -9,6 +9,30 @@ export interface DiffLine {
   old_index: number | null;
   new_index: number | null;
   value: string;
+  new_value: string | null;
+  word_spans: InlineSpan[];
+  char_spans: InlineSpan[];
+  move_index: number | null;
+  /** Part of a hunk of only blank lines, hidden by ignore_blank_lines */
+  ignored: boolean;
+}
+
+/** Emphasized range within a line, in UTF-16 code units (JS string indices) */
+export interface InlineSpan {
+  start: number;
+  end: number;
+}
+
+export type DiffAlgorithm = 'myers' | 'patience' | 'lcs' | 'histogram';
+
+export interface DiffOptions {
+  ignore_all_whitespace: boolean;
+  ignore_whitespace_amount: boolean;
+  ignore_trailing_whitespace: boolean;
+  ignore_case: boolean;
+  ignore_blank_lines: boolean;
+  algorithm: DiffAlgorithm;
+  timeout_ms: number | null;
 }
 
 export interface DiffStats {
@@ -17,9 +41,18 @@ export interface DiffStats {
   unchanged: number;
 }
 
+/** Lines deleted at old_start and inserted at new_start (0-based) */
+export interface MovedBlock {
+  old_start: number;
+  new_start: number;
+  count: number;
+  exact: boolean;
+}
+
 export interface DiffResult {
   lines: DiffLine[];
   stats: DiffStats;
+  moves: MovedBlock[];
 }
 
 export interface FileContent {
@@ -30,8 +63,15 @@ export interface FileContent {
   line_count: number;
   is_binary: boolean;
   exists: boolean;
+  /** Pass to write_file as expectedVersion to refuse saving over external changes */
+  version: string | null;
 }
 
+/** Rejection value of write_file */
+export type WriteError =
+  | { kind: 'modified-on-disk'; path: string; expected: string; actual: string | null }
+  | { kind: 'io'; message: string };
+
 export interface FileDiffResult {
   left: FileContent;
   right: FileContent;
@@ -39,7 +79,7 @@ export interface FileDiffResult {
 }
 
 // Three-way merge types
-export type ChunkType = 'Equal' | 'LocalOnly' | 'RemoteOnly' | 'Conflict';
+export type ChunkType = 'Equal' | 'LocalOnly' | 'RemoteOnly' | 'BothSame' | 'Conflict';
 
 export interface MergeChunk {
   chunk_type: ChunkType;
@@ -49,10 +89,24 @@ export interface MergeChunk {
   remote_lines: string[];
 }
 
+export type ConflictStyle = 'merge' | 'diff3' | 'zdiff3';
+
+export interface MergeOptions {
+  conflict_style: ConflictStyle;
+  local_label: string;
+  base_label: string;
+  remote_label: string;
+  marker_size: number;
+  algorithm: DiffAlgorithm;
+}
+
 export interface MergeResult {
   chunks: MergeChunk[];
   conflict_count: number;
   merged_content: string;
+  line_ending: 'lf' | 'crlf';
+  final_newline: boolean;
+  line_ending_mismatch: string | null;
 }
 
 export type CompareMode = 'file' | 'directory' | 'merge';
@@ -69,47 +123,77 @@ export interface RecentComparison {
 // CLI args from backend
 export type CliMode =
   | { mode: 'None' }
-  | { mode: 'Diff'; left: string; right: string }
+  | { mode: 'Diff'; left: string; right: string; options: DiffOptions }
   | { mode: 'DirDiff'; left: string; right: string }
-  | { mode: 'Merge'; local: string; base: string; remote: string; output: string | null };
+  | {
+      mode: 'Merge';
+      local: string;
+      base: string;
+      remote: string;
+      output: string | null;
+      options: MergeOptions;
+    }
+  | {
+      mode: 'DirMerge';
+      local: string;
+      base: string;
+      remote: string;
+      options: MergeOptions;
+    };
 
 // Directory comparison types
-export type FileStatus = 'Identical' | 'Modified' | 'LeftOnly' | 'RightOnly';
+/** How directory scans treat dotfiles */
+export type HiddenFiles = 'include' | 'exclude' | 'ignore-rules';
 
-export interface CompareEntry {
-  name: string;
-  rel_path: string;
-  is_dir: boolean;
-  status: FileStatus;
-  left_size: number | null;
-  right_size: number | null;
-  children: CompareEntry[];
+export interface ScanOptions {
+  hidden_files?: HiddenFiles;
+  follow_symlinks?: boolean;
+  compare_metadata?: boolean;
+  /** Levels below the root to scan; unlimited when omitted */
+  max_depth?: number | null;
 }
 
+/** Options for compare_directories / compare_directories_async */
+export interface CompareOptions extends ScanOptions {
+  ignore_patterns?: string[];
+  method?: CompareMethod;
+}
+
+/** How equal-sized files are checked for identical content */
+export type CompareMethod = 'size' | 'hash' | 'bytes';
+
 export interface CompareStats {
   identical: number;
   modified: number;
   left_only: number;
   right_only: number;
+  metadata_only: number;
   total_files: number;
 }
 
-export interface DirectoryCompareResult {
-  left_path: string;
-  right_path: string;
-  entries: CompareEntry[];
-  stats: CompareStats;
-}
-
 // Single directory scan types
+/** 'symlink' is a link that wasn't followed; 'other' covers FIFOs, sockets, devices */
+export type EntryKind = 'file' | 'dir' | 'symlink' | 'other';
+
 export interface DirEntry {
   name: string;
   rel_path: string;
   is_dir: boolean;
   size: number;
+  kind: EntryKind;
+  link_target: string | null;
+  metadata: FileMetadata | null;
   children: DirEntry[];
 }
 
+export interface FileMetadata {
+  mode: number | null;
+  /** Seconds since the Unix epoch */
+  mtime: number | null;
+  uid: number | null;
+  gid: number | null;
+}
+
 export interface ScanResult {
   root_path: string;
   entries: DirEntry[];
@@ -117,7 +201,7 @@ export interface ScanResult {
 }
 
 // Aligned directory comparison types (new backend)
-export type EntryStatus = 'match' | 'modified' | 'leftonly' | 'rightonly';
+export type EntryStatus = 'match' | 'modified' | 'leftonly' | 'rightonly' | 'metadataonly';
 
 export interface AlignedEntry {
   name: string;
@@ -125,6 +209,12 @@ export interface AlignedEntry {
   is_dir: boolean;
   left_size: number | null;
   right_size: number | null;
+  left_kind: EntryKind | null;
+  right_kind: EntryKind | null;
+  left_link_target: string | null;
+  right_link_target: string | null;
+  left_metadata: FileMetadata | null;
+  right_metadata: FileMetadata | null;
   status: EntryStatus;
   children: AlignedEntry[];
 }
@@ -136,8 +226,120 @@ export interface AlignedScanResult {
   stats: CompareStats;
 }
 
+/** Streamed over the channel passed to compare_directories_async */
+export type CompareEvent =
+  // Entries of the directory at `parent` ('' for the roots); listed
+  // directories arrive first without children
+  | { kind: 'batch'; parent: string; entries: AlignedEntry[] }
+  | { kind: 'complete'; stats: CompareStats };
+
 export interface ScanProgress {
   phase: string;
   files: number;
   message: string;
 }
+
+// Three-way directory merge types
+export type MergeState =
+  | 'unchanged'
+  | 'changed-local'
+  | 'changed-remote'
+  | 'changed-both'
+  | 'added-local'
+  | 'added-remote'
+  | 'added-both'
+  | 'deleted-local'
+  | 'deleted-remote'
+  | 'deleted-both'
+  | 'add-add-conflict'
+  | 'modify-delete-conflict';
+
+export interface MergeEntry {
+  name: string;
+  rel_path: string;
+  is_dir: boolean;
+  local_kind: EntryKind | null;
+  base_kind: EntryKind | null;
+  remote_kind: EntryKind | null;
+  local_size: number | null;
+  base_size: number | null;
+  remote_size: number | null;
+  state: MergeState;
+  /** Conflicts left by the automatic merge of a text file changed on both sides */
+  conflict_count: number | null;
+  /** Needs a manual decision; for directories, whether anything inside does */
+  has_conflict: boolean;
+  children: MergeEntry[];
+}
+
+export interface MergeStats {
+  unchanged: number;
+  local_changes: number;
+  remote_changes: number;
+  both_changes: number;
+  conflicts: number;
+  total_files: number;
+}
+
+export interface DirMergeResult {
+  root_local: string;
+  root_base: string;
+  root_remote: string;
+  entries: MergeEntry[];
+  stats: MergeStats;
+}
+
+// Directory sync types
+export type SyncAction = 'copy-to-right' | 'copy-to-left' | 'delete-left' | 'delete-right';
+
+export interface SyncItem {
+  rel_path: string;
+  action: SyncAction;
+}
+
+export interface SyncOperation {
+  rel_path: string;
+  action: SyncAction;
+  is_dir: boolean;
+  /** Whether a copy writes over something at the target; directories are merged */
+  replaces: boolean;
+  bytes: number;
+}
+
+export interface SyncItemResult {
+  rel_path: string;
+  action: SyncAction;
+  error: string | null;
+}
+
+export interface SyncReport {
+  root_left: string;
+  root_right: string;
+  dry_run: boolean;
+  operations: SyncOperation[];
+  /** One per operation; empty for a dry run */
+  results: SyncItemResult[];
+}
+
+/** Emitted as 'sync-progress' while sync_directories runs */
+export interface SyncProgress {
+  done: number;
+  total: number;
+  rel_path: string;
+}
+
+// App-managed trash types
+export interface TrashedEntry {
+  rel_path: string;
+  is_dir: boolean;
+  trashed_path: string;
+}
+
+/** Returned by delete_entries; pass `id` to undo_delete to restore */
+export interface TrashBatch {
+  id: string;
+  root: string;
+  /** Seconds since the Unix epoch */
+  created: number;
+  entries: TrashedEntry[];
+}